@@ -1,7 +1,7 @@
-/// Program that collects data from AISHub.net
-/// 
-/// Author: G0rocks
-/// Date created: 2025-10-20
+//! Program that collects data from AISHub.net
+//! 
+//! Author: G0rocks
+//! Date created: 2025-10-20
 
 // Crate imports
 use serde::Deserialize; // For deserializing JSON
@@ -12,26 +12,280 @@ use std::fs;        // For file system operations
 use reqwest;      // For making HTTP requests
 use time;     // For handling time
 use std::{io}; // To use errors
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering}; // For the shutdown flag and health state
+use std::io::{Read, Seek, Write}; // For the health check server and checking the end of vessel files
+use arrow_array; // For building the record batches written to Parquet files
+use arrow_schema; // For the schema of the Parquet files
+use parquet; // For reading and writing Parquet files
+use flate2; // For gzip compressed vessel files
 
 // Constants
 /// Minutes to increase interval by if too frequent requests are made. Set to the minimum allowed by AISHub (1 minute at 2025-11-04).
 const INTERVAL_DEFAULT_INCREMENT: u32 = 1;
+/// Lowest update interval in minutes, AISHub does not allow more than one request a minute
+const MIN_UPDATE_INTERVAL: u32 = 1;
 /// List of invalid filename characters to be replaced with an underscore
 const INVALID_FILENAME_CHARACTERS: [char; 9] = ['\\', '/',':','*','?','"','<','>','|'];
+/// File that records which could not be read are appended to so they can be inspected later
+const QUARANTINE_FILE: &str = "quarantine.csv";
+/// Default number of seconds to sleep at a time between cycles before checking for setting changes and shutdown
+const SLEEP_GRANULARITY_DEFAULT: u64 = 5;
+/// Default number of API errors in a row before a webhook notification is sent
+const WEBHOOK_ERROR_THRESHOLD_DEFAULT: u32 = 3;
+/// Minimum number of minutes between webhook notifications so an outage does not spam the webhook
+const WEBHOOK_MIN_INTERVAL: u64 = 30;
+/// Default number of cycles between pruning records older than retention_days
+const RETENTION_CHECK_CYCLES_DEFAULT: u64 = 60;
+/// Default max number of ships per request, more ships are split into several requests to keep the URL short
+const BATCH_SIZE_DEFAULT: usize = 100;
+/// Default number of seconds to wait between requests when the ships are split into several requests. Set to the minimum allowed by AISHub (1 minute at 2025-11-04).
+const BATCH_DELAY_DEFAULT: u64 = 60;
+/// Default settings file
+const SETTINGS_FILE_JSON: &str = "settings.json";
+/// Settings file used instead of settings.json if it exists and settings.json does not
+const SETTINGS_FILE_TOML: &str = "settings.toml";
+/// Placeholder API key written to settings.json by --init
+const API_KEY_PLACEHOLDER: &str = "YOUR_AISHUB_API_KEY";
+/// Environment variable that overrides api_key in the settings file if it is set
+const API_KEY_ENV_VAR: &str = "AISHUB_API_KEY";
+/// Default number of empty cycles in a row before warning that the filters may be wrong
+const EMPTY_CYCLES_WARNING_DEFAULT: u32 = 10;
+/// Default number of decimal places written for float columns, 6 decimal places of a degree is about 0.1 m
+const COORDINATE_PRECISION_DEFAULT: usize = 6;
+/// Number of cycles the average cycle timings are worked out over
+const TIMING_AVERAGE_CYCLES: usize = 10;
+/// Seconds to wait before retrying a request that failed with a network error
+const RETRY_DELAY: u64 = 5;
+/// Number of characters of the response body included in the error when AISHub returns an unsuccessful HTTP status
+const ERROR_BODY_SNIPPET_LENGTH: usize = 200;
+/// Log level where only errors and warnings are printed
+const LOG_LEVEL_QUIET: u8 = 0;
+/// Log level where the status of each cycle is printed as well
+const LOG_LEVEL_NORMAL: u8 = 1;
+/// Log level where details about the requests and parsing are printed as well
+const LOG_LEVEL_VERBOSE: u8 = 2;
+/// Seconds to wait before trying to save again after a write error
+const WRITE_RETRY_DELAY: u64 = 5;
+/// Default number of times to try saving again after a write error when on_write_error is "retry"
+const WRITE_RETRIES_DEFAULT: u32 = 3;
+/// Max seconds between tries to save again when on_write_error is "pause"
+const WRITE_PAUSE_MAX_DELAY: u64 = 300;
+/// Number of successful cycles in a row before an automatically increased update interval is decreased by INTERVAL_DEFAULT_INCREMENT
+const INTERVAL_DECAY_CYCLES: u64 = 60;
+/// Minutes the age limit is lowered by between the requests of --backfill if no step is given
+const BACKFILL_STEP_DEFAULT: u64 = 60;
+/// Number of ships with the most records listed by --stats
+const STATS_BUSIEST_SHIPS: usize = 10;
+/// Lock file in the data folder, locked while an instance is writing to the data folder
+const LOCK_FILE: &str = "data/.lock";
+/// Max distance in nautical miles between two positions for them to be the same position when checking for near duplicates, about 18 meters
+const DEDUP_DISTANCE_NM: f64 = 0.01;
+/// Folder of the Parquet files when storage_format is "parquet"
+const PARQUET_FOLDER: &str = "data/parquet";
+/// File records without an IMO or MMSI number are saved to when keep_unidentified is set
+const UNIDENTIFIED_FILE: &str = "data/unidentified.csv";
+/// Number of failed AISHub requests in a row before the fallback source is used, used if fallback_after_errors is not set
+const FALLBACK_AFTER_ERRORS_DEFAULT: u32 = 3;
+/// Number of seconds a record timestamp may be ahead of the clock, used if max_future_skew_secs is not set
+const MAX_FUTURE_SKEW_SECS_DEFAULT: u64 = 300;
+/// Maximum number of redirects followed for a request
+const MAX_REDIRECTS: usize = 5;
+/// SQLite database of the "sqlite" sink, used if sqlite_path is not set
+const SQLITE_FILE_DEFAULT: &str = "data/vessels.sqlite";
+/// Percentage max_requests_per_hour is lowered by after too frequent requests if AISHub does not say how long to wait
+const REQUEST_BUDGET_DECREASE_PERCENT: u32 = 25;
+/// Address of the AISHub API, used if base_url is not set
+const AISHUB_BASE_URL_DEFAULT: &str = "https://data.aishub.net/ws.php";
+
+/// Names of the standard columns in the order of the VesselInfo fields and the vessel files.
+/// Used for the headers of the vessel files, the values written to them and the order of the headers in the CSV responses so they can not drift apart
+const STANDARD_COLUMNS: [&str; 22] = ["A", "B", "C", "CALLSIGN", "COG", "D", "DEST", "DRAUGHT", "DEVICE", "ETA", "HEADING", "IMO", "LATITUDE", "LONGITUDE", "MMSI", "NAME", "NAVSTAT", "PAC", "ROT", "SOG", "TSTAMP", "TYPE"];
+
+// Statics
+/// Set to true when ctrl+C is pressed so the program can stop cleanly
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Unix timestamp of the last successful collection, 0 if there has been none. Read by the health check server
+static LAST_SUCCESS: AtomicI64 = AtomicI64::new(0);
+/// Max number of seconds since the last successful collection for the health check to report healthy
+static HEALTH_MAX_AGE_SECS: AtomicU64 = AtomicU64::new(0);
+/// Log level from the settings, kept here so functions without the settings can check it. Updated every time the settings are read
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LOG_LEVEL_NORMAL);
+/// Set by --stdout so log messages go to stderr and stdout only has data
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+/// Number of requests made to AISHub each cycle, i.e. the number of batches. Used to work out the time between cycles from max_requests_per_hour
+static REQUESTS_PER_CYCLE: AtomicU64 = AtomicU64::new(1);
+/// Unix timestamp of the next cycle while sleeping outside active_hours, 0 if it has not slept outside them. The health check reports healthy until then
+static SLEEPING_UNTIL: AtomicI64 = AtomicI64::new(0);
+
+// Macros
+/// Prints a log message to stdout, or to stderr if LOG_TO_STDERR is set.
+/// Shadows the standard println! so every log message in this file follows --stdout
+macro_rules! println {
+    ($($arg:tt)*) => {
+        if LOG_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            std::println!($($arg)*)
+        }
+    };
+}
 
 fn main() {
+    // Get command line arguments
+    let args: Vec<String> = std::env::args().collect();
+
+    // Run other commands instead of collecting data
+    match args.get(1).map(|a| a.as_str()) {
+        // Replay a saved response
+        Some("--replay") => {
+            let _lock = match lock_data_folder() {
+                Some(l) => l,
+                None => return,
+            };
+            match args.get(2) {
+                Some(file_path) => replay_response(file_path),
+                None => println!("Usage: --replay <file>"),
+            }
+            return;
+        },
+        // Make settings.json and ships.csv files for first time setup
+        Some("--init") => {
+            init_files();
+            return;
+        },
+        // Merge vessel files with the same identifier
+        Some("--compact") => {
+            let _lock = match lock_data_folder() {
+                Some(l) => l,
+                None => return,
+            };
+            match get_settings() {
+                Ok(settings) => {
+                    match compact_data(&settings) {
+                        Ok(_) => println!("Done compacting data folder."),
+                        Err(e) => println!("Error compacting data folder: {}", e),
+                    }
+                },
+                Err(e) => println!("Error getting settings from settings.json file: {}", e),
+            }
+            return;
+        },
+        // Collect one update for the ships already in the data folder, ignoring ships.csv
+        Some("--refresh-existing") => {
+            let _lock = match lock_data_folder() {
+                Some(l) => l,
+                None => return,
+            };
+            refresh_existing();
+            return;
+        },
+        // Request older positions once for each age limit from the given number of minutes down to now
+        Some("--backfill") => {
+            let max_age = match args.get(2).and_then(|a| a.parse::<u64>().ok()) {
+                Some(m) => m,
+                None => {
+                    println!("Usage: --backfill <minutes> [step minutes]");
+                    return;
+                }
+            };
+            let step = args.get(3).and_then(|a| a.parse::<u64>().ok()).unwrap_or(BACKFILL_STEP_DEFAULT).max(1);
+            let _lock = match lock_data_folder() {
+                Some(l) => l,
+                None => return,
+            };
+            backfill(max_age, step);
+            return;
+        },
+        // Write previously collected records to stdout at the pace they were collected
+        Some("--simulate") => {
+            let dir = match args.get(2) {
+                Some(d) => d,
+                None => {
+                    println!("Usage: --simulate <dir> [speed]");
+                    return;
+                }
+            };
+            let speed = args.get(3).and_then(|a| a.parse::<f64>().ok()).filter(|s| *s > 0.0).unwrap_or(1.0);
+            simulate(dir, speed);
+            return;
+        },
+        // Write a ship's track to a GPX file
+        Some("--export-gpx") => {
+            match args.get(2) {
+                Some(identifier) => {
+                    match export_gpx(identifier, args.get(3).map(|a| a.as_str())) {
+                        Ok(_) => {},
+                        Err(e) => println!("Error exporting GPX: {}", e),
+                    }
+                },
+                None => println!("Usage: --export-gpx <imo or mmsi number> [output file]"),
+            }
+            return;
+        },
+        // Check the API key and the connection to AISHub with a single request
+        Some("--check-api") => {
+            check_api();
+            return;
+        },
+        // List the ships in ships.csv and the data folder with their last record
+        Some("--list-ships") => {
+            // Use default settings if settings.json can not be read, they are only needed for id_priority and header aliases
+            let settings = get_settings().unwrap_or_default();
+            match list_ships(&settings) {
+                Ok(_) => {},
+                Err(e) => println!("Error listing ships: {}", e),
+            }
+            return;
+        },
+        // Print a summary of the collected data
+        Some("--stats") => {
+            // Use default settings if settings.json can not be read, they are only needed for header aliases
+            let settings = get_settings().unwrap_or_default();
+            match print_data_stats(&settings) {
+                Ok(_) => {},
+                Err(e) => println!("Error reading data folder: {}", e),
+            }
+            return;
+        },
+        _ => {}
+    }
+
+    // Write the records to stdout instead of saving them if set, log messages then go to stderr
+    let to_stdout = args.iter().any(|a| a == "--stdout");
+    LOG_TO_STDERR.store(to_stdout, Ordering::Relaxed);
+
+    // Make a default settings.json on first run and stop so the API key can be added, unless it is given by the environment variable
+    if !std::path::Path::new(get_settings_path()).exists() {
+        set_settings(&Settings::default());
+        if std::env::var(API_KEY_ENV_VAR).is_ok_and(|k| !k.trim().is_empty()) {
+            println!("Created default {}, using the API key from {}.", get_settings_path(), API_KEY_ENV_VAR);
+        } else {
+            println!("Created default {} — please add your API key.", get_settings_path());
+            return;
+        }
+    }
+
     // Startup message
     println!("Starting AISHub Data Collector... Press ctrl+C to stop.");
+
+    // Lock the data folder so a second instance does not write to the same files, the lock is released when the program stops
+    // Nothing is written to the data folder with --stdout so it is not locked
+    let lock_file = if to_stdout {
+        None
+    } else {
+        match lock_data_folder() {
+            Some(l) => Some(l),
+            None => return,
+        }
+    };
     // Init start time
     let start_time = time::UtcDateTime::now();
 
-    // Init default update_interval (in minutes)
-    let mut update_interval: u32;
-
-    // Get list of ships to monitor
-    let (imo_nums, mmsi_nums) = get_list_of_ships();
-    let imo = vec_to_delimiter_separated_string(&imo_nums, ';');
-    let mmsi = vec_to_delimiter_separated_string(&mmsi_nums, ';');
+    // Set shutdown flag when ctrl+C is pressed
+    match ctrlc::set_handler(|| SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst)) {
+        Ok(_) => {},
+        Err(e) => println!("Error setting ctrl+C handler, the program will stop immediately when ctrl+C is pressed: {}", e),
+    };
 
     // Initialize settings
     let mut settings: Settings = match get_settings() {
@@ -40,48 +294,286 @@ fn main() {
             panic!("Error getting initial settings from settings.json file: {}", e);
         }
     };
+    enforce_min_update_interval(&mut settings);
 
-    // Infinite loop to collect data periodically
-    loop {
+    // Get list of ships to monitor
+    let (imo_nums, mmsi_nums, labels) = get_list_of_ships(&settings);
+
+    // Stop if no API key is set since every request would fail
+    if !settings.get_api_keys().iter().all(|k| is_api_key_set(k)) {
+        println!("Set your AISHub API key in settings.json");
+        return;
+    }
+
+    // Explain what is collected if ships.csv has no ships, e.g. only the header or a placeholder row from --init
+    if imo_nums.is_empty() && mmsi_nums.is_empty() {
+        let has_bounding_box = settings.lat_min.is_some() || settings.lat_max.is_some() || settings.lon_min.is_some() || settings.lon_max.is_some();
+        if settings.polygon.is_some() {
+            println!("No ships listed in ships.csv, collecting every ship inside the polygon set in settings.json.");
+        } else if has_bounding_box {
+            println!("No ships listed in ships.csv, collecting every ship inside the bounding box set in settings.json.");
+        } else {
+            // Warn loudly so collecting the whole feed is an intentional choice
+            println!("--------------------------------------------------------------------------------------");
+            println!("WARNING: no ships listed in ships.csv and no bounding box set in settings.json.");
+            println!("Collecting everything your account provides, which may be nothing or the whole feed.");
+            println!("Add the imo and/or mmsi numbers of ships to ships.csv or set lat_min, lat_max, lon_min and lon_max in settings.json to limit the data collected.");
+            println!("--------------------------------------------------------------------------------------");
+        }
+    }
+
+    // Warn about vessel files that would be the same file on case insensitive file systems
+    warn_about_case_variant_files();
+
+    // Start health check server if a port is set
+    match settings.health_port {
+        Some(port) => start_health_server(port),
+        None => {}
+    }
+
+    // Init API key rotation
+    let mut key_rotator = KeyRotator::new();
+
+    // Init HTTP client which is reused between cycles
+    let mut http_client = ReusableClient::new();
+
+    // Init webhook notifier and number of API errors in a row
+    let mut notifier = WebhookNotifier::new();
+    let mut consecutive_errors: u32 = 0;
+
+    // Init whether the fallback source was used last cycle
+    let mut using_fallback = false;
+
+    // Init number of successful requests in a row and how many minutes the update interval has been increased automatically
+    let mut consecutive_successes: u64 = 0;
+    let mut auto_interval_increase: u32 = 0;
+
+    // Init cycle counter and number of cycles in a row with no vessels returned
+    let mut cycle_count: u64 = 0;
+    let mut consecutive_empty_cycles: u32 = 0;
+
+    // Init per ship summary of the records collected this session
+    let mut session_summary: std::collections::BTreeMap<String, ShipSummary> = std::collections::BTreeMap::new();
+
+    // Init map of the IMO numbers seen for each MMSI number this session
+    let mut mmsi_to_imo: std::collections::HashMap<u64, u64> = std::collections::HashMap::new();
+
+    // Init latest timestamp of each ship written to stdout and write the headers, so --stdout skips duplicates like the vessel files do
+    let mut stdout_timestamps: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    if to_stdout {
+        match write_headers_to_stdout(&settings) {
+            Ok(_) => {},
+            Err(e) => println!("Error writing headers to stdout: {}", e),
+        }
+    }
+
+    // Init seconds between cycles worked out from max_requests_per_hour, logged when it changes
+    let mut budget_interval_secs: u64 = 0;
+
+    // Init timings of the last few cycles
+    let mut recent_timings: std::collections::VecDeque<CycleTimings> = std::collections::VecDeque::new();
+
+    // Wait for active_hours to start before the first cycle, unless data is also collected outside them
+    if settings.inactive_interval.is_none() {
+        sleep_until_active_hours(&mut settings);
+    }
+
+    // Loop to collect data periodically until ctrl+C is pressed
+    while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
         // Print status message
         let now = time::UtcDateTime::now();
         let runtime = now - start_time;
-        println!("{}-{:02}-{:02} {:02}:{:02}:{:02} - Collecting data from AISHub for {:.1}", now.year(), now.month() as u8, now.day(), now.hour(), now.minute(), now.second(), runtime);
+        if !is_quiet() {
+            println!("{} - Collecting data from AISHub for {:.1}", format_datetime(now), runtime);
+        }
         // update settings from settings file in case they changed
         match get_settings() {
             Ok(s) => settings = s,
-            Err(e) => println!("Error getting settings from settings.json file: {}\nUsing previous settings.", e),
+            Err(e) => println!("Warning: Error getting settings from settings file: {}\nUsing previous settings.", e),
         };
+        enforce_min_update_interval(&mut settings);
+
+        // Split ships into batches so the URLs do not get too long
+        let batches = make_ship_batches(&imo_nums, &mmsi_nums, settings.batch_size.unwrap_or(BATCH_SIZE_DEFAULT));
+
+        // Work out the time between cycles again in case the number of batches or max_requests_per_hour changed
+        REQUESTS_PER_CYCLE.store(batches.len() as u64, Ordering::SeqCst);
+        if settings.max_requests_per_hour.is_some() && settings.cycle_interval_secs() != budget_interval_secs {
+            budget_interval_secs = settings.cycle_interval_secs();
+            println!("{} requests per cycle, waiting {} seconds between cycles to stay within max_requests_per_hour ({}).", batches.len(), budget_interval_secs, settings.max_requests_per_hour.unwrap_or_default());
+        }
+
+        // Update health check freshness window in case the settings changed
+        HEALTH_MAX_AGE_SECS.store(settings.health_max_age.map(|minutes| minutes * 60).unwrap_or(settings.cycle_interval_secs() * 3), Ordering::SeqCst);
 
-        // Update update_interval from settings
-        update_interval = settings.update_interval;
+        // Get the HTTP client, reused so connections are kept alive between cycles
+        let client = match http_client.get(&settings) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("Error making HTTP client: {}\nTrying again after {} minute/s.", e, settings.update_interval);
+                sleep_until_next_cycle(&mut settings);
+                continue;
+            }
+        };
 
-        // Make URL
-        let url = make_aishub_url(settings.api_key.as_str(), settings.data_value_format, settings.output_format.as_str(), settings.compression, settings.lat_min, settings.lat_max, settings.lon_min, settings.lon_max, mmsi.as_deref(), imo.as_deref(), settings.age_max);
+        // Pick the API key to use this cycle
+        let api_key = match key_rotator.next_key(&settings) {
+            Some(k) => k,
+            None => {
+                println!("All API keys are resting after too frequent requests. Trying again after {} minute/s.", settings.update_interval);
+                sleep_until_next_cycle(&mut settings);
+                continue;
+            }
+        };
 
         // Collect data using API
-        let data =  match get_data_from_aishub_api(url, &settings) {
-            Ok(d) => d,
+        let mut timings = CycleTimings::default();
+        let data =  match get_data_in_batches(&batches, api_key.as_str(), &client, &settings, &mut timings) {
+            Ok((d, None)) => {
+                if using_fallback {
+                    println!("AISHub API is working again, switching back from the fallback source.");
+                    using_fallback = false;
+                }
+                consecutive_errors = 0;
+                consecutive_successes += 1;
+
+                // Bring the update interval back down step by step after it was increased automatically and requests have succeeded for a while
+                if auto_interval_increase > 0 && consecutive_successes >= INTERVAL_DECAY_CYCLES {
+                    let decrease = auto_interval_increase.min(INTERVAL_DEFAULT_INCREMENT).min(settings.update_interval.saturating_sub(1));
+                    if decrease > 0 {
+                        settings.update_interval -= decrease;
+                        set_settings(&settings);
+                        println!("No too frequent requests for {} cycles, decreasing update interval by {} minute/s to {} minute/s.", consecutive_successes, decrease, settings.update_interval);
+                    }
+                    auto_interval_increase -= auto_interval_increase.min(INTERVAL_DEFAULT_INCREMENT);
+                    consecutive_successes = 0;
+                }
+                d
+            },
+            // A later batch failed, the records from the batches before it are still saved
+            Ok((d, Some(e))) => {
+                consecutive_errors += 1;
+                consecutive_successes = 0;
+                if let CollectError::RateLimited(body) = &e {
+                    handle_rate_limited(body, api_key.as_str(), &settings, &client, &mut key_rotator, &mut notifier);
+
+                    // Update update_interval from settings in case it was increased
+                    let previous_interval = settings.update_interval;
+                    match get_settings() {
+                        Ok(s) => settings = s,
+                        Err(e) => println!("Warning: Error getting settings from settings file: {}\nUsing previous settings.", e),
+                    };
+                    enforce_min_update_interval(&mut settings);
+                    auto_interval_increase += settings.update_interval.saturating_sub(previous_interval);
+                }
+                println!("Error getting data from AISHub API: {}\nSaving the {} records from the batches before the error.", e, d.len());
+                d
+            },
+            // Stop right away if ctrl+C was pressed between requests
+            Err(CollectError::Interrupted) => break,
             // Skip this iteration and try again after sleep
             Err(e) => {
-                // Update update_interval from settings in case it was changed, check if updated settings
-                match get_settings() {
-                    Ok(s) => settings = s,
-                    Err(e) => println!("Error getting settings from settings.json file: {}\nUsing previous settings.", e),
+                // Notify webhook if too frequent requests were made or the errors have gone on for too long
+                consecutive_errors += 1;
+                if let CollectError::RateLimited(body) = &e {
+                    handle_rate_limited(body, api_key.as_str(), &settings, &client, &mut key_rotator, &mut notifier);
+                } else if consecutive_errors >= settings.webhook_error_threshold.unwrap_or(WEBHOOK_ERROR_THRESHOLD_DEFAULT) {
+                    notifier.notify(&settings, &client, format!("{} errors in a row getting data from AISHub API. Latest error: {}", consecutive_errors, e).as_str());
+                }
+
+                consecutive_successes = 0;
+
+                // Get the data from the fallback source instead during an AISHub outage, AISHub is still tried first every cycle
+                let fallback_data = match &settings.fallback_url_template {
+                    Some(template) if consecutive_errors >= settings.fallback_after_errors.unwrap_or(FALLBACK_AFTER_ERRORS_DEFAULT) && !matches!(e, CollectError::RateLimited(_)) => {
+                        if !using_fallback {
+                            println!("{} errors in a row from AISHub API, switching to the fallback source. Latest error: {}", consecutive_errors, e);
+                        }
+                        match get_data_from_fallback(template, &batches, &client, &settings, &mut timings) {
+                            Ok(d) => Some(d),
+                            Err(CollectError::Interrupted) => break,
+                            Err(fallback_error) => {
+                                println!("Error getting data from the fallback source: {}", fallback_error);
+                                None
+                            }
+                        }
+                    },
+                    _ => None,
                 };
-                update_interval = settings.update_interval;
-                // Notify user
-                println!("Error getting data from AISHub API: {}\nTrying again after {} minute/s.", e, update_interval);
-                // Wait until next interval
-                std::thread::sleep(std::time::Duration::from_secs((update_interval * 60) as u64));
-                // Continue to next iteration
-                continue;
+                match fallback_data {
+                    Some(d) => {
+                        using_fallback = true;
+                        d
+                    },
+                    None => {
+                        // Update update_interval from settings in case it was changed, check if updated settings
+                        let previous_interval = settings.update_interval;
+                        match get_settings() {
+                            Ok(s) => settings = s,
+                            Err(e) => println!("Warning: Error getting settings from settings file: {}\nUsing previous settings.", e),
+                        };
+                        enforce_min_update_interval(&mut settings);
+                        // Remember how much the interval was increased automatically so it can be decreased again later
+                        if let CollectError::RateLimited(_) = e {
+                            auto_interval_increase += settings.update_interval.saturating_sub(previous_interval);
+                        }
+                        // Notify user
+                        println!("Error getting data from AISHub API: {}\nTrying again after {} minute/s.", e, settings.update_interval);
+                        // Wait until next interval
+                        sleep_until_next_cycle(&mut settings);
+                        // Continue to next iteration
+                        continue;
+                    }
+                }
             }
         };
 
-        // Store data in database
-        match save_data(&data) {
-            Ok(_) => {},
+        // Log number of vessels returned and warn if nothing has been returned for a while
+        if !is_quiet() {
+            println!("{} vessels returned this cycle.", data.len());
+        }
+        if data.is_empty() {
+            consecutive_empty_cycles += 1;
+            let warning_threshold = settings.empty_cycles_warning.unwrap_or(EMPTY_CYCLES_WARNING_DEFAULT);
+            if consecutive_empty_cycles == warning_threshold {
+                println!("Warning: No vessels have been returned for {} cycles in a row. Check that the imo/mmsi numbers in ships.csv and the bounding box in settings.json are correct.", consecutive_empty_cycles);
+            }
+        } else {
+            consecutive_empty_cycles = 0;
+        }
+
+        // Drop records outside the time window to store, records of ships moving slower than min_sog and records outside the polygon
+        let data = filter_future_timestamps(data, &settings);
+        let data = filter_time_window(data, &settings);
+        let data = filter_min_sog(data, &settings);
+        let mut data = filter_polygon(data, &settings);
+
+        // Fill in missing IMO numbers of known ships so their data is not split between the imo and mmsi files
+        reconcile_imo_numbers(&mut data, &mut mmsi_to_imo, &settings);
+
+        // Store data in database, or write it to stdout if set
+        let save_start = std::time::Instant::now();
+        let saved = if to_stdout {
+            write_data_to_stdout(&data, &mut stdout_timestamps, &labels, &settings)
+        } else {
+            save_data_with_policy(&data, &labels, &settings)
+        };
+        match saved {
+            Ok((written, stats)) => {
+                if !is_quiet() {
+                    println!("Saved data: {}.", stats);
+                }
+                LAST_SUCCESS.store(time::UtcDateTime::now().unix_timestamp(), Ordering::SeqCst);
+                // Also write the new records to InfluxDB if set
+                match &settings.influxdb_url {
+                    Some(url) => write_to_influxdb(&written, url, &client, &settings),
+                    None => {}
+                }
+                // Add the written records to the session summary
+                for vessel in written {
+                    session_summary.entry(get_ship_key(vessel, &settings)).or_default().add(vessel);
+                }
+            },
             Err(e) => {
                 let mut filename_imo: Vec<String> = Vec::new();
                 let mut filename_mmsi: Vec<String> = Vec::new();
@@ -96,9 +588,47 @@ fn main() {
                 println!("Error saving data to database.\nPotential troublemaking filenames:\n - {:?}\n - {:?}\nIgnoring and continuing.\nError message: {}\nData: {:?}", filename_imo, filename_mmsi, e, &data);
             }
         };
+        timings.save = save_start.elapsed();
+
+        // Log how long each step took, along with the average over the last few cycles
+        recent_timings.push_back(timings);
+        if recent_timings.len() > TIMING_AVERAGE_CYCLES {
+            recent_timings.pop_front();
+        }
+        if !is_quiet() {
+            println!("Cycle timings: {} (average over last {} cycles: {})", timings, recent_timings.len(), CycleTimings::average(&recent_timings));
+        }
+
+        // Prune old records every few cycles if a retention period is set
+        match settings.retention_days {
+            Some(days) => {
+                if cycle_count.is_multiple_of(settings.retention_check_cycles.unwrap_or(RETENTION_CHECK_CYCLES_DEFAULT).max(1)) {
+                    match prune_old_records(days, &settings) {
+                        Ok(_) => {},
+                        Err(e) => println!("Error pruning records older than {} days: {}\nIgnoring and continuing.", days, e),
+                    }
+                }
+            },
+            None => {}
+        }
+        cycle_count += 1;
 
         // Wait until next interval
-        std::thread::sleep(std::time::Duration::from_secs((update_interval * 60) as u64));
+        sleep_until_next_cycle(&mut settings);
+    }
+
+    // Shutdown message
+    println!("Stopping AISHub Data Collector.");
+    drop(lock_file);
+    print_session_summary(&session_summary);
+    match &settings.webhook_url {
+        Some(url) => {
+            match http_client.get(&settings) {
+                Ok(client) => send_webhook_message(url, "AISHub Data Collector stopped.", &client),
+                Err(e) => println!("Error making HTTP client for webhook notification: {}", e),
+            }
+        },
+        None => {}
     }
 }
 
@@ -116,7 +646,395 @@ struct Settings {
     lat_max: Option<f64>,
     lon_min: Option<f64>,
     lon_max: Option<f64>,
-    age_max: Option<u64>
+    /// Max age of the positions returned by AISHub, sent as the interval parameter of the API.
+    /// Either a number of minutes or a string with a unit, e.g. "90m", "2h" or "1d". No limit if not set
+    age_max: Option<Minutes>,
+    /// Max size of a vessel file in megabytes before it is rolled over to a numbered file. No limit if not set
+    max_file_mb: Option<u64>,
+    /// If true, an ETA_DECODED column with the ETA in MM-DD HH:MM format is added to the vessel files
+    #[serde(default)]
+    decode_eta: bool,
+    /// Proxy URL to route the API requests through, e.g. http://proxy.example.com:8080 or socks5://127.0.0.1:1080
+    proxy: Option<String>,
+    /// Number of seconds to sleep at a time between cycles before checking for setting changes and ctrl+C. Defaults to SLEEP_GRANULARITY_DEFAULT
+    sleep_granularity: Option<u64>,
+    /// Directory to save the raw API responses to before parsing, for replaying with --replay. Not saved if not set
+    save_raw_responses: Option<String>,
+    /// If true, a CUM_DIST_NM column with the total distance traveled in nautical miles is added to the vessel files
+    #[serde(default)]
+    cumulative_distance: bool,
+    /// Slack/Discord compatible incoming webhook URL to notify on errors, too frequent requests and shutdown
+    webhook_url: Option<String>,
+    /// Number of API errors in a row before the webhook is notified. Defaults to WEBHOOK_ERROR_THRESHOLD_DEFAULT
+    webhook_error_threshold: Option<u32>,
+    /// Number of days to keep records for, older records are pruned from the vessel files. Records are kept forever if not set
+    retention_days: Option<u64>,
+    /// Number of cycles between pruning old records. Defaults to RETENTION_CHECK_CYCLES_DEFAULT
+    retention_check_cycles: Option<u64>,
+    /// If true, vessel files with no records left after pruning are deleted
+    #[serde(default)]
+    retention_delete_empty: bool,
+    /// Port to serve the /healthz health check on. No health check server if not set
+    health_port: Option<u16>,
+    /// Max minutes since the last successful collection for /healthz to report healthy. Defaults to 3 times update_interval
+    health_max_age: Option<u64>,
+    /// Max number of ships per request. Defaults to BATCH_SIZE_DEFAULT
+    batch_size: Option<usize>,
+    /// Number of seconds to wait between requests when the ships are split into several requests. Defaults to BATCH_DELAY_DEFAULT
+    batch_delay: Option<u64>,
+    /// Number of cycles in a row with no vessels returned before warning that the filters may be wrong. Defaults to EMPTY_CYCLES_WARNING_DEFAULT
+    empty_cycles_warning: Option<u32>,
+    /// If true, SOG is always stored in knots and COG in degrees whichever data_value_format is used, and "not available" values are stored as empty fields
+    #[serde(default)]
+    normalize_sog_cog: bool,
+    /// Custom header names for the vessel files, e.g. {"MMSI": "mmsi", "SOG": "speed_over_ground"}. Headers without an alias keep the standard name
+    header_aliases: Option<std::collections::HashMap<String, String>>,
+    /// Number of decimal places written for float columns (COG, SOG, latitude, longitude and CUM_DIST_NM). Defaults to COORDINATE_PRECISION_DEFAULT
+    coordinate_precision: Option<usize>,
+    /// Several API keys to rotate through round-robin, one key per cycle. Used instead of api_key if set.
+    /// If too frequent requests are made with one of them, only that key rests instead of the update interval being increased
+    api_keys: Option<Vec<String>>,
+    /// Path of a single .csv file every stored record is also appended to, e.g. "data/all_vessels.csv". Only records that are new for their vessel are appended
+    append_combined: Option<String>,
+    /// Number of times a request that failed with a network error or timeout is retried within a cycle, RETRY_DELAY seconds apart. Not retried if not set
+    retries_per_cycle: Option<u32>,
+    /// Only records with a timestamp at or after this unix timestamp are stored. No lower limit if not set
+    store_since: Option<u64>,
+    /// Only records with a timestamp at or before this unix timestamp are stored. No upper limit if not set
+    store_until: Option<u64>,
+    /// How much is printed, "quiet" (only errors and warnings), "normal" or "verbose" (also the URLs requested with the API key redacted and parsing notes). Defaults to "normal"
+    #[serde(default)]
+    log_level: String,
+    /// Address of the API without parameters, e.g. a local mock server for testing or a proxy. Defaults to AISHUB_BASE_URL_DEFAULT
+    base_url: Option<String>,
+    /// If true, a ROT_DPM column with the rate of turn decoded to degrees per minute is added to the vessel files
+    #[serde(default)]
+    decode_rot: bool,
+    /// If true, a RECV_TSTAMP column with the unix timestamp of when the record was saved is added to the vessel files
+    #[serde(default)]
+    record_receive_time: bool,
+    /// If true, a LABEL column with the label of the ship from the optional label column in ships.csv is added to the vessel files
+    #[serde(default)]
+    label_column: bool,
+    /// InfluxDB write URL, e.g. "http://localhost:8086/api/v2/write?org=myorg&bucket=ais" or "http://localhost:8086/write?db=ais".
+    /// If set, the new records of each cycle are also written to InfluxDB as vessel_position points in one request
+    influxdb_url: Option<String>,
+    /// InfluxDB API token, sent as "Authorization: Token <token>". No token is sent if not set
+    influxdb_token: Option<String>,
+    /// What to do when saving data fails, e.g. when the disk is full: "skip" (default) moves on to the next cycle,
+    /// "retry" tries again up to write_retries times and "pause" keeps trying again with a growing delay until the data is saved
+    #[serde(default)]
+    on_write_error: String,
+    /// Number of times to try saving again when on_write_error is "retry". Defaults to WRITE_RETRIES_DEFAULT
+    write_retries: Option<u32>,
+    /// When fields in the vessel files are quoted: "necessary" (default) only when needed, "always" or "never"
+    #[serde(default)]
+    quote_style: String,
+    /// Max update interval in minutes that too frequent requests can increase update_interval to. No limit if not set
+    max_update_interval: Option<u32>,
+    /// If true, an AGE_SECS column with how many seconds old the record was when it was saved (receive time - TSTAMP) is added to the vessel files
+    #[serde(default)]
+    record_age: bool,
+    /// Records with a speed over ground below this many knots are not stored, e.g. to leave out anchored and moored ships. No minimum if not set
+    min_sog: Option<f64>,
+    /// If true, records with SOG "not available" are still stored when min_sog is set, otherwise they are left out too
+    #[serde(default)]
+    min_sog_keep_unavailable: bool,
+    /// If true, each vessel gets a folder with one file per day, e.g. data/imo/{id}/{YYYY-MM-DD}.csv, instead of one growing file.
+    /// The day is the UTC date of the record's TSTAMP, so duplicates are still caught at a day boundary. CUM_DIST_NM starts from 0 in each day's file
+    #[serde(default)]
+    partition_by_date: bool,
+    /// If true, each vessel file gets a metadata file next to it, e.g. NAME_123.meta.json, with the formats the data was collected in,
+    /// the collector version and when data was first and last collected for it
+    #[serde(default)]
+    file_metadata: bool,
+    /// Max number of records kept in each vessel file, the oldest records are removed when a new one is saved. No limit if not set
+    max_records_per_ship: Option<usize>,
+    /// Vertices of a polygon as [latitude, longitude] pairs in degrees, e.g. [[59.9, 10.6], [59.9, 10.8], [59.7, 10.6]].
+    /// Only records with a position inside the polygon are stored. AISHub has no polygon filter so the records are filtered after they are received,
+    /// and the bounds of the polygon are requested instead of lat_min, lat_max, lon_min and lon_max where those are not set. No polygon if not set
+    polygon: Option<Vec<(f64, f64)>>,
+    /// Max number of seconds added at random to each sleep between cycles, so several collectors started together do not all make their requests at the same time. No jitter if not set
+    jitter_secs: Option<u64>,
+    /// If set, a SOURCE column with this tag is added to the vessel files, e.g. "aishub", to tell the rows apart after merging data from several sources
+    source_tag: Option<String>,
+    /// api_key from the settings file when it is overridden by the AISHUB_API_KEY environment variable, so set_settings writes it back instead of the environment variable
+    #[serde(skip)]
+    api_key_in_file: Option<String>,
+    /// Which identifier is used when a ship has both, "imo" (default) or "mmsi". Decides both the number requested for ships listed with both in ships.csv
+    /// and whether a record is saved to data/imo or data/mmsi
+    #[serde(default)]
+    id_priority: String,
+    /// Max speed in knots implied by the distance and time to the previous record in the vessel file. Faster records are suspect, e.g. GPS spoofing or a bad fix. No check if not set
+    max_implied_speed_knots: Option<f64>,
+    /// What to do with suspect records, "skip" (default) to not save them or "flag" to save them with SUSPECT=1 in a SUSPECT column
+    #[serde(default)]
+    on_suspect_position: String,
+    /// How collected data is stored, "csv" (default) for a CSV file per vessel or "parquet" for a Parquet file per day with all vessels in data/parquet
+    #[serde(default)]
+    storage_format: String,
+    /// If true, a GEOM_WKT column with the position as WKT in degrees, e.g. "POINT(5.2 60.1)", is added to the vessel files. Empty if the position is unknown
+    #[serde(default)]
+    wkt_column: bool,
+    /// If true, records without an IMO or MMSI number are saved to UNIDENTIFIED_FILE instead of being dropped
+    #[serde(default)]
+    keep_unidentified: bool,
+    /// If true, leading and trailing spaces are removed from vessel names and spaces inside them are collapsed to one before they are used for filenames and stored
+    #[serde(default)]
+    normalize_names: bool,
+    /// If true and normalize_names is set, vessel names are also uppercased
+    #[serde(default)]
+    uppercase_names: bool,
+    /// A record within this many seconds of the last record in its vessel file and at essentially the same position is skipped as a near duplicate. 0 or not set to only skip records that are not newer
+    dedup_window_secs: Option<u64>,
+    /// If true, new vessel files are gzip compressed, e.g. NAME_123.csv.gz. Takes less space but the whole file is written again every time a record is added
+    #[serde(default)]
+    compress_output: bool,
+    /// Bounding box as a single string, "lat_min,lon_min,lat_max,lon_max", read into lat_min, lon_min, lat_max and lon_max when the settings are loaded.
+    /// Can not be used together with those settings
+    bbox: Option<String>,
+    /// URL of another AIS source with the same response format, used while AISHub is failing. May contain {imo}, {mmsi}, {lat_min}, {lat_max}, {lon_min} and {lon_max},
+    /// which are replaced with the values of each request, or left empty if not set
+    fallback_url_template: Option<String>,
+    /// Number of failed AISHub requests in a row before the fallback source is used. Defaults to FALLBACK_AFTER_ERRORS_DEFAULT
+    fallback_after_errors: Option<u32>,
+    /// Records with a timestamp more than this many seconds ahead of the clock are skipped. Defaults to MAX_FUTURE_SKEW_SECS_DEFAULT
+    max_future_skew_secs: Option<u64>,
+    /// If true, a FLAG column with the flag state from the MMSI number, e.g. "Norway", is added to the vessel files. Empty if it is unknown
+    #[serde(default)]
+    flag_column: bool,
+    /// Path of the vessel files with placeholders, e.g. "data/{id_type}/{type_category}/{name}_{id}.csv", see make_templated_path. Must end with .csv and contain {id}.
+    /// Defaults to the normal layout, data/imo/NAME_123.csv or data/mmsi/NAME_123.csv
+    path_template: Option<String>,
+    /// If true, a SEQ column with the number of the record in its vessel file is added to the vessel files, counting on from the last record and starting at 0
+    #[serde(default)]
+    sequence_column: bool,
+    /// If true, the dimensions (A, B, C and D), DRAUGHT and TYPE are written as empty fields instead of 0 when they are unknown, so they can not be mistaken for a measured zero
+    #[serde(default)]
+    unknown_as_empty: bool,
+    /// If true, the files written in a cycle are synced to disk with fsync before the next step, so a power loss can not lose records that were already logged as saved
+    #[serde(default)]
+    fsync_after_write: bool,
+    /// Where the collected data is stored, any of "csv", "parquet" and "sqlite". Each cycle's records are written to all of them. storage_format is used if empty
+    #[serde(default)]
+    sinks: Vec<String>,
+    /// Path of the SQLite database of the "sqlite" sink, SQLITE_FILE_DEFAULT if not set
+    sqlite_path: Option<String>,
+    /// Hours in UTC when data is collected every update_interval, e.g. [6, 22] from 06:00 to 22:00 or [22, 6] from 22:00 to 06:00. Always if not set
+    active_hours: Option<(u8, u8)>,
+    /// Minutes between cycles outside active_hours. If not set, no data is collected outside active_hours
+    inactive_interval: Option<u32>,
+    /// Case of the vessel names in the filenames, "upper" (default) for NAME_123.csv, "lower" for name_123.csv or "keep" to use the name as it is received
+    #[serde(default)]
+    filename_case: String,
+    /// If true, the values changed by normalize_sog_cog and normalize_names are also written as received to SOG_RAW, COG_RAW and NAME_RAW columns
+    #[serde(default)]
+    keep_raw_columns: bool,
+    /// Max number of requests to AISHub per hour. The time between cycles is worked out from it and the number of requests per cycle, and is never shorter than update_interval.
+    /// Lowered automatically after too frequent requests instead of increasing update_interval
+    max_requests_per_hour: Option<u32>,
+}
+
+impl Default for Settings {
+    /// Default settings, the API key is empty and no bounding box is set
+    fn default() -> Settings {
+        Settings {
+            api_key: String::new(),
+            update_interval: 5,
+            data_value_format: 1,
+            output_format: "csv".to_string(),
+            compression: 0,
+            lat_min: None,
+            lat_max: None,
+            lon_min: None,
+            lon_max: None,
+            age_max: None,
+            max_file_mb: None,
+            decode_eta: false,
+            proxy: None,
+            sleep_granularity: None,
+            save_raw_responses: None,
+            cumulative_distance: false,
+            webhook_url: None,
+            webhook_error_threshold: None,
+            retention_days: None,
+            retention_check_cycles: None,
+            retention_delete_empty: false,
+            health_port: None,
+            health_max_age: None,
+            batch_size: None,
+            batch_delay: None,
+            empty_cycles_warning: None,
+            normalize_sog_cog: false,
+            header_aliases: None,
+            coordinate_precision: None,
+            api_keys: None,
+            append_combined: None,
+            retries_per_cycle: None,
+            store_since: None,
+            store_until: None,
+            log_level: "normal".to_string(),
+            base_url: None,
+            decode_rot: false,
+            record_receive_time: false,
+            label_column: false,
+            influxdb_url: None,
+            influxdb_token: None,
+            on_write_error: "skip".to_string(),
+            write_retries: None,
+            quote_style: "necessary".to_string(),
+            max_update_interval: None,
+            record_age: false,
+            min_sog: None,
+            min_sog_keep_unavailable: false,
+            partition_by_date: false,
+            file_metadata: false,
+            max_records_per_ship: None,
+            polygon: None,
+            jitter_secs: None,
+            source_tag: None,
+            api_key_in_file: None,
+            id_priority: "imo".to_string(),
+            max_implied_speed_knots: None,
+            on_suspect_position: "skip".to_string(),
+            storage_format: "csv".to_string(),
+            wkt_column: false,
+            keep_unidentified: false,
+            normalize_names: false,
+            uppercase_names: false,
+            dedup_window_secs: None,
+            compress_output: false,
+            bbox: None,
+            fallback_url_template: None,
+            fallback_after_errors: None,
+            max_future_skew_secs: None,
+            flag_column: false,
+            path_template: None,
+            sequence_column: false,
+            unknown_as_empty: false,
+            fsync_after_write: false,
+            sinks: Vec::new(),
+            sqlite_path: None,
+            active_hours: None,
+            inactive_interval: None,
+            filename_case: "upper".to_string(),
+            keep_raw_columns: false,
+            max_requests_per_hour: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Gets the number of seconds between cycles: update_interval, or the time needed to stay within max_requests_per_hour with REQUESTS_PER_CYCLE requests each cycle if that is longer
+    fn cycle_interval_secs(&self) -> u64 {
+        let interval = self.update_interval as u64 * 60;
+        match self.max_requests_per_hour {
+            Some(budget) => return interval.max((REQUESTS_PER_CYCLE.load(Ordering::SeqCst) * 3600).div_ceil(budget.max(1) as u64)),
+            None => return interval,
+        }
+    }
+
+    /// Gets age_max in minutes, None if not set or invalid
+    fn age_max_minutes(&self) -> Option<u64> {
+        return self.age_max.as_ref().and_then(|a| a.to_minutes().ok());
+    }
+
+    /// Gets the bounding box to request as (lat_min, lat_max, lon_min, lon_max)
+    /// Bounds that are not set are taken from the polygon if one is set, so less data is received that would be filtered out anyway
+    fn bounding_box(&self) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+        let vertices = self.polygon.as_deref().unwrap_or_default();
+        let lats = vertices.iter().map(|v| v.0);
+        let lons = vertices.iter().map(|v| v.1);
+        return (
+            self.lat_min.or(lats.clone().reduce(f64::min)),
+            self.lat_max.or(lats.reduce(f64::max)),
+            self.lon_min.or(lons.clone().reduce(f64::min)),
+            self.lon_max.or(lons.reduce(f64::max)),
+        );
+    }
+
+    /// Checks if MMSI numbers are used before IMO numbers, see id_priority
+    fn prefers_mmsi(&self) -> bool {
+        return self.id_priority == "mmsi";
+    }
+
+    /// Gets the API keys to rotate through, api_keys if set and not empty, otherwise api_key
+    fn get_api_keys(&self) -> Vec<String> {
+        match &self.api_keys {
+            Some(keys) if !keys.is_empty() => return keys.clone(),
+            _ => return vec![self.api_key.clone()],
+        }
+    }
+
+    /// Gets the sinks the data is written to, sinks if not empty, otherwise storage_format
+    fn get_sinks(&self) -> Vec<String> {
+        if !self.sinks.is_empty() {
+            return self.sinks.clone();
+        }
+        match self.storage_format.as_str() {
+            "" => return vec!["csv".to_string()],
+            format => return vec![format.to_string()],
+        }
+    }
+
+    /// Gets the name a standard header is written as in the vessel files, i.e. its alias if one is set
+    fn header_alias(&self, header: &str) -> String {
+        match &self.header_aliases {
+            Some(aliases) => return aliases.get(header).cloned().unwrap_or(header.to_string()),
+            None => return header.to_string(),
+        }
+    }
+
+    /// Checks if suspect records are saved with a SUSPECT column instead of being skipped, see max_implied_speed_knots
+    fn flags_suspect_positions(&self) -> bool {
+        return self.max_implied_speed_knots.is_some() && self.on_suspect_position == "flag";
+    }
+
+    /// Gets the standard header name of a header read from a vessel file, i.e. reverses header_alias
+    fn standard_header(&self, header: &str) -> String {
+        match &self.header_aliases {
+            Some(aliases) => {
+                match aliases.iter().find(|(_, alias)| alias.as_str() == header) {
+                    Some((standard, _)) => return standard.clone(),
+                    None => return header.to_string(),
+                }
+            },
+            None => return header.to_string(),
+        }
+    }
+}
+
+/// A number of minutes given either as a number, e.g. 90, or as a string with a unit, e.g. "90m", "2h" or "1d"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Minutes {
+    Number(u64),
+    Text(String),
+}
+
+impl Minutes {
+    /// Gets the number of minutes. Strings without a unit are read as minutes
+    fn to_minutes(&self) -> Result<u64, String> {
+        match self {
+            Minutes::Number(n) => return Ok(*n),
+            Minutes::Text(text) => {
+                let text = text.trim().to_lowercase();
+                // Split number and unit
+                let split_index = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+                let (number, unit) = text.split_at(split_index);
+                let number: u64 = match number.parse() {
+                    Ok(n) => n,
+                    Err(_) => return Err(format!("\"{}\" does not start with a number", text)),
+                };
+                match unit.trim() {
+                    "" | "m" | "min" | "mins" | "minute" | "minutes" => return Ok(number),
+                    "h" | "hour" | "hours" => return Ok(number * 60),
+                    "d" | "day" | "days" => return Ok(number * 60 * 24),
+                    other => return Err(format!("Unknown unit \"{}\", use m, h or d", other)),
+                }
+            }
+        }
+    }
 }
 
 /// The ship info received from AISHub API
@@ -132,8 +1050,8 @@ struct VesselInfo {
     c:  u64,
     /// vessel’s callsign. If unknown, value is empty string
     callsign:   String,
-    /// Course Over Ground AIS format – in 1/10 degrees i.e. degrees multiplied by 10. COG=3600 means “not available” Human readable format – degrees. COG=360.0 means “not available” 
-    cog:    f64,
+    /// Course Over Ground AIS format – in 1/10 degrees i.e. degrees multiplied by 10. COG=3600 means “not available” Human readable format – degrees. COG=360.0 means “not available”. None if it could not be read, or if not available after normalize_sog_cog
+    cog:    Option<f64>,
     /// Dimension to Starboard (meters). If unknown, value is zero
     d:  u64,
     /// vessel’s destination. If unknown, value is empty string
@@ -160,11 +1078,17 @@ struct VesselInfo {
     navstat:    String,
     /// (AIS format only) – Position Accuracy 0 – low accuracy 1 – high accuracy. If unknown, low accuracy is assumed and value is zero
     pac:   u8,
+    /// COG as received, before it is changed by normalize_sog_cog. Written to the COG_RAW column if keep_raw_columns is set
+    raw_cog:    String,
+    /// Vessel name as received, before it is changed by normalize_names. Written to the NAME_RAW column if keep_raw_columns is set
+    raw_name:   String,
+    /// SOG as received, before it is changed by normalize_sog_cog. Written to the SOG_RAW column if keep_raw_columns is set
+    raw_sog:    String,
     /// (AIS format only) - Rate of Turn. If unknown, value is empty string
     rot:    String,
-    /// Speed Over Ground AIS format – in 1/10 knots i.e. knots multiplied by 10. SOG=1024 means “not available” Human readable format – knots. SOG=102.4 means “not available” 
-    sog:    u64,
-    ///  	data timestamp AIS format – unix timestamp Human readable format – UTC. If unknown, value is zero
+    /// Speed Over Ground AIS format – in 1/10 knots i.e. knots multiplied by 10. SOG=1024 means “not available” Human readable format – knots. SOG=102.4 means “not available”. None if it could not be read, or if not available after normalize_sog_cog
+    sog:    Option<f64>,
+    /// data timestamp AIS format – unix timestamp Human readable format – UTC. If unknown, value is zero
     timestamp: u64,
     /// vessel’s type. If unknown, value is zero
     vessel_type:   u64,
@@ -178,7 +1102,7 @@ impl VesselInfo {
             b: 0,
             c: 0,
             callsign: String::new(),
-            cog: 3600.0,
+            cog: Some(3600.0),
             d: 0,
             dest: String::new(),
             draught: 0,
@@ -192,536 +1116,5498 @@ impl VesselInfo {
             name: String::new(),
             navstat: String::new(),
             pac: 0,
+            raw_cog: String::new(),
+            raw_name: String::new(),
+            raw_sog: String::new(),
             rot: String::new(),
-            sog: 1024,
+            sog: Some(1024.0),
             timestamp: 0,
             vessel_type: 0,
         }
     }
 }
 
+/// The headers and last record of a vessel file
+struct LastRecord {
+    headers: csv::StringRecord,
+    /// None if the file has no records
+    record: Option<csv::StringRecord>,
+    /// Number of records in the file
+    count: usize,
+}
 
-// Functions
-// --------------------------------------------------------------------------------------
+impl LastRecord {
+    /// Gets a value from the last record by header name. Returns None if there is no record or no such column
+    fn get(&self, header: &str) -> Option<&str> {
+        let index = self.headers.iter().position(|h| h == header)?;
+        return self.record.as_ref()?.get(index);
+    }
 
-/// Gets settings from settings file
-/// API key, loop interval (in minutes)
-fn get_settings() -> Result<Settings, io::Error> {
-    // Parse settings.json file
-    let contents = match fs::read_to_string("settings.json") {
-        Ok(c) => c,
-        Err(e) => {
-            return Err(io::Error::new(io::ErrorKind::NotFound, std::format!("Error reading settings.json file: {}", e)));
+    /// Gets the timestamp of the last record. Returns 0 if the file has no records
+    fn timestamp(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        match &self.record {
+            Some(_) => return Ok(self.get("TSTAMP").ok_or("Missing TSTAMP field in last record of CSV file")?.parse()?),
+            None => return Ok(0),
         }
-    };
-    let settings: Settings = serde_json::from_str(&contents).expect("Error parsing settings.json file");
-
-    // Return settings
-    return Ok(settings);
+    }
 }
 
-/// Sets the settings in the settings file
-fn set_settings(settings: &Settings) {
-    // Serialize settings to JSON
-    let contents = serde_json::to_string_pretty(&settings).expect("Error serializing settings to JSON");
-
-    // Write settings to settings.json file
-    match fs::write("settings.json", contents) {
-        Ok(_) => {},
-        Err(e) => {
-            panic!("Error writing settings to settings.json file: {}", e);
-        }
-    };
+/// Sends rate limited notifications to the webhook in the settings
+struct WebhookNotifier {
+    /// When the last notification was sent, None if none has been sent
+    last_sent: Option<std::time::Instant>,
 }
 
-/// Gets list of ships to monitor from ships.csv file
-/// Returns a tuple of two vectors: (mmsi_numbers, imo_numbers)
-/// Prioritizes IMO numbers over MMSI numbers so if both are provided, IMO is used
-fn get_list_of_ships() -> (Vec<String>, Vec<String>) {
-    println!("Getting list of ships!");
-    let mut mmsi: Vec<String> = Vec::new();
-    let mut imo: Vec<String> = Vec::new();
+impl WebhookNotifier {
+    /// Creates a new notifier which has not sent any notifications
+    fn new() -> WebhookNotifier {
+        WebhookNotifier { last_sent: None }
+    }
 
-    // Read ships.csv file
-    let mut rdr = match csv::ReaderBuilder::new()
-        // Allow variable number of fields per record
-        .flexible(true)
-        .has_headers(true)
-        .from_path("ships.csv") {
-            Ok(r) => r,
-            Err(e) => panic!("Error reading ships.csv file: {}", e),
+    /// Sends a message to the webhook if one is set and no notification has been sent in the last WEBHOOK_MIN_INTERVAL minutes
+    /// client is the HTTP client of the AISHub requests, so the proxy and redirect policy apply to the notifications as well
+    fn notify(&mut self, settings: &Settings, client: &reqwest::blocking::Client, message: &str) {
+        // Do nothing if no webhook is set
+        let url = match &settings.webhook_url {
+            Some(u) => u,
+            None => return,
         };
 
-    // For each entry, if MMSI or IMO is provided, add to respective vector
-    for result in rdr.records() {
-        let record = match result {
-            Ok(r) => r,
-            Err(e) => {
-                // Notify user and skip this record
-                println!("Error reading record from ships.csv file, ignoring and moving on.\nRecord ignored: {}", e);
-                continue;
-            }
-        };
-        // If imo number is provided, add to imo vector
-        if !record[0].is_empty() {
-            imo.push(record[0].to_string());
-            continue;
+        // Skip if a notification was sent recently
+        match self.last_sent {
+            Some(t) if t.elapsed() < std::time::Duration::from_secs(WEBHOOK_MIN_INTERVAL * 60) => return,
+            _ => {}
         }
-        if record[1].is_empty() {
-            continue; // Skip if both are empty
-        }
-        // Add mmsi number
-        mmsi.push(record[1].to_string());
+
+        send_webhook_message(url, message, client);
+        self.last_sent = Some(std::time::Instant::now());
     }
+}
 
-    // Return tuple of vectors
-    return (imo, mmsi);
+/// Rotates through the API keys in the settings round-robin, skipping keys that are resting after too frequent requests
+struct KeyRotator {
+    /// Index of the key to try next
+    next_index: usize,
+    /// When each resting key may be used again
+    resting_until: std::collections::HashMap<String, std::time::Instant>,
 }
 
-/// Takes in a vector of strings and returns a single string with the delimiter between the values
-/// E.g. if the delimiter is a semicomma: ["123", "456", "789"] -> "123;456;789"
-fn vec_to_delimiter_separated_string(vec: &Vec<String>, delimiter: char) -> Option<String> {
-    // Return None if vector is empty
-    if vec.is_empty() {
-        return None;
+impl KeyRotator {
+    /// Creates a new rotator starting at the first key with no keys resting
+    fn new() -> KeyRotator {
+        KeyRotator { next_index: 0, resting_until: std::collections::HashMap::new() }
     }
 
-    // Loop through vector and build string
-    let mut result = String::new();
-    for (i, value) in vec.iter().enumerate() {
-        result.push_str(value);
-        if i < vec.len() - 1 {
-            result.push(delimiter); // Add delimiter if not the last value
+    /// Gets the next API key that is not resting. Returns None if all keys are resting
+    fn next_key(&mut self, settings: &Settings) -> Option<String> {
+        let keys = settings.get_api_keys();
+        for _ in 0..keys.len() {
+            let key = &keys[self.next_index % keys.len()];
+            self.next_index = (self.next_index + 1) % keys.len();
+            match self.resting_until.get(key) {
+                Some(until) if std::time::Instant::now() < *until => continue,
+                _ => return Some(key.clone()),
+            }
         }
+        return None;
     }
 
-    return Some(result);
+    /// Stops a key from being used for the given number of minutes
+    fn rest(&mut self, key: &str, minutes: u32) {
+        self.resting_until.insert(key.to_string(), std::time::Instant::now() + std::time::Duration::from_secs(minutes as u64 * 60));
+    }
 }
 
-/// Makes the URL for the AISHub API request
-/// Based on https://www.aishub.net/api
-fn make_aishub_url(api_key: &str, data_value_format: u8, output_format: &str, compression: u8, lat_min: Option<f64>, lat_max: Option<f64>, lon_min: Option<f64>, lon_max: Option<f64>, mmsi: Option<&str>, imo: Option<&str>, age_max: Option<u64>) -> String {
-    let mut url = format!("https://data.aishub.net/ws.php?username={}&format={}&output={}&compress={}", api_key, data_value_format, output_format, compression);
+/// Keeps one HTTP client for the AISHub API requests so connections are reused between cycles
+/// The client is made again if the proxy in the settings changes
+struct ReusableClient {
+    /// The client, None until the first request
+    client: Option<reqwest::blocking::Client>,
+    /// Proxy the client was made with
+    proxy: Option<String>,
+}
 
-    // Add optional parameters
-    match lat_min {
-        Some(value) => url.push_str(&format!("&latmin={}", value)),
-        None => {}
-    }
-    match lat_max {
-        Some(value) => url.push_str(&format!("&latmax={}", value)),
-        None => {}
-    }
-    match lon_min {
-        Some(value) => url.push_str(&format!("&lonmin={}", value)),
-        None => {}
-    }
-    match lon_max {
-        Some(value) => url.push_str(&format!("&lonmax={}", value)),
-        None => {}
+impl ReusableClient {
+    /// Creates a new ReusableClient which makes the client on first use
+    fn new() -> ReusableClient {
+        ReusableClient { client: None, proxy: None }
     }
-    match mmsi {
-        Some(value) => url.push_str(&format!("&mmsi={}", value)),
-        None => {}
+
+    /// Gets the client, making it if there is none yet or the proxy has changed
+    fn get(&mut self, settings: &Settings) -> Result<reqwest::blocking::Client, reqwest::Error> {
+        match &self.client {
+            Some(client) if self.proxy == settings.proxy => return Ok(client.clone()),
+            _ => {}
+        }
+        let client = make_http_client(settings)?;
+        self.client = Some(client.clone());
+        self.proxy = settings.proxy.clone();
+        return Ok(client);
     }
-    match imo {
-        Some(value) => url.push_str(&format!("&imo={}", value)),
-        None => {}
+}
+
+/// Time spent on each step of a collection cycle
+#[derive(Debug, Default, Clone, Copy)]
+struct CycleTimings {
+    /// Time spent making requests and downloading the responses
+    fetch: std::time::Duration,
+    /// Time spent parsing the responses
+    parse: std::time::Duration,
+    /// Time spent saving the data to the vessel files
+    save: std::time::Duration,
+}
+
+impl CycleTimings {
+    /// Works out the average timings of the given cycles
+    fn average(timings: &std::collections::VecDeque<CycleTimings>) -> CycleTimings {
+        let count = timings.len().max(1) as u32;
+        return CycleTimings {
+            fetch: timings.iter().map(|t| t.fetch).sum::<std::time::Duration>() / count,
+            parse: timings.iter().map(|t| t.parse).sum::<std::time::Duration>() / count,
+            save: timings.iter().map(|t| t.save).sum::<std::time::Duration>() / count,
+        };
     }
-    match age_max {
-        Some(value) => url.push_str(&format!("&interval={}", value)),
-        None => {}
+}
+
+impl std::fmt::Display for CycleTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "fetch {:.1}s, parse {:.1}s, save {:.1}s", self.fetch.as_secs_f64(), self.parse.as_secs_f64(), self.save.as_secs_f64());
     }
+}
 
-    // Return URL
-    return url;
+/// Number of records written and skipped when saving the data of a cycle, logged after each cycle
+#[derive(Debug, Default, Clone, Copy)]
+struct SaveStats {
+    /// Records written to the vessel files, the Parquet files or stdout
+    written: usize,
+    /// Records skipped because they are not newer than the last record of the ship or are at the same position within dedup_window_secs
+    deduped: usize,
+    /// Records skipped for other reasons, i.e. suspect positions, records without an IMO or MMSI number that are not kept and records over max_records_per_ship
+    skipped: usize,
+    /// Records without an IMO or MMSI number written to UNIDENTIFIED_FILE when keep_unidentified is set
+    unidentified: usize,
 }
 
-/// Function that fetches data from AISHub API given a URL
-/// Assumes only 1 data point is returned per ship
-fn get_data_from_aishub_api(url: String, settings: &Settings) -> Result<Vec<VesselInfo>, io::Error> {
-    // Get the result of the request
-    let body = match reqwest::blocking::get(url) {
-        Ok(response) => {
-            match response.text() {
-                Ok(text) => text,
-                Err(e) => {
-                    return Err(io::Error::new(io::ErrorKind::Other, std::format!("Error reading response text: {}", e)));
-                }
-            }
-        },
-        Err(e) => {
-            return Err(io::Error::new(io::ErrorKind::Other, std::format!("Error making request to AISHub API: {}", e)));
+impl std::fmt::Display for SaveStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "wrote {} new, {} duplicates skipped, {} other records skipped", self.written, self.deduped, self.skipped)?;
+        if self.unidentified > 0 {
+            write!(f, ", {} without an IMO or MMSI number saved to {}", self.unidentified, UNIDENTIFIED_FILE)?;
         }
-    };
+        return Ok(());
+    }
+}
 
-    // If too frequent requests are made, stop running
-    if body == "Too frequent requests!" {
-        // Increase update interval by 1 and return error
-        let mut settings_modified = settings.clone();
-        settings_modified.update_interval += INTERVAL_DEFAULT_INCREMENT;
-        set_settings(&settings_modified);
-        println!("Too frequent requests made to AISHub API. Increasing update interval in settings by {} minute. Please check your update interval and make sure it is big enough.", INTERVAL_DEFAULT_INCREMENT);
-        return Err(io::Error::new(io::ErrorKind::QuotaExceeded, body));
+/// Summary of the records written for a ship this session, printed on shutdown
+#[derive(Debug, Default)]
+struct ShipSummary {
+    /// Latest name of the ship
+    name: String,
+    /// Number of records written
+    records: u64,
+    /// Timestamp of the first record written
+    first_timestamp: u64,
+    /// Timestamp of the last record written
+    last_timestamp: u64,
+}
+
+impl ShipSummary {
+    /// Adds a written record to the summary
+    fn add(&mut self, vessel: &VesselInfo) {
+        if self.records == 0 || vessel.timestamp < self.first_timestamp {
+            self.first_timestamp = vessel.timestamp;
+        }
+        if vessel.timestamp > self.last_timestamp {
+            self.last_timestamp = vessel.timestamp;
+        }
+        self.name = vessel.name.clone();
+        self.records += 1;
     }
 
-    // Get CSV reader from body
-    let mut rdr = csv::Reader::from_reader(body.as_bytes());
+    /// Gets the average number of minutes between records, None if there are fewer than 2 records
+    fn average_interval_minutes(&self) -> Option<f64> {
+        if self.records < 2 {
+            return None;
+        }
+        return Some((self.last_timestamp - self.first_timestamp) as f64 / (self.records - 1) as f64 / 60.0);
+    }
+}
 
-    // Get order of headers
-    let headers = rdr.headers().unwrap().clone();
-    let header_order = get_header_order(&headers);
+/// Values of the optional columns that depend on previously stored data
+/// Values are None if the column is not enabled in the settings
+#[derive(Debug, Default)]
+struct DerivedColumns {
+    /// Total distance traveled in nautical miles
+    cum_dist_nm: Option<f64>,
+    /// Unix timestamp of when the record was saved
+    recv_tstamp: Option<i64>,
+    /// Label of the ship from ships.csv
+    label: Option<String>,
+    /// Seconds between the record timestamp and when the record was saved
+    age_secs: Option<i64>,
+    /// True if the implied speed from the previous record is above max_implied_speed_knots, None if it could not be checked
+    suspect: Option<bool>,
+    /// Number of the record in its vessel file, one more than the last record
+    seq: Option<u64>,
+}
 
-    // Init empty vector to hold data
-    let mut data: Vec<VesselInfo> = Vec::new();
+/// A ship with vessel files in the data folder, as listed by --list-ships
+#[derive(Debug, Default)]
+struct ListedShip {
+    /// Number of vessel files, including rolled over and daily files
+    files: usize,
+    /// Newest timestamp in the files, None if none of them has a record with a timestamp
+    newest: Option<u64>,
+    /// Vessel name in the newest record
+    name: String,
+    /// IMO number in the newest record
+    imo: u64,
+    /// MMSI number in the newest record
+    mmsi: u64,
+}
 
-    // Loop through each line of the response body, append each data point to data vector
-    for result in rdr.records() {
-        let record = match result {
-            Ok(r) => r,
-            Err(e) => {
-                // Notify user and skip this record
-                println!("Error reading record from CSV response, ignoring and moving on.\nRecord ignored: {}", e);
-                continue;
+/// Provenance of a vessel file, saved next to it when file_metadata is set so old data can be read correctly later
+#[derive(Debug, Deserialize, Serialize)]
+struct FileMetadata {
+    /// Format of the responses from AISHub, "csv" or "xml"
+    output_format: String,
+    /// 0 if the values are in AIS format, 1 if they are human readable
+    data_value_format: u8,
+    /// If SOG and COG were converted to knots and degrees
+    normalize_sog_cog: bool,
+    /// Version of the collector that made the metadata file
+    collector_version: String,
+    /// Unix timestamp of when the metadata file was made, i.e. when the first record was saved unless the file is older than the metadata file
+    first_collected: i64,
+    /// Unix timestamp of when a record was last saved, updated at most once a day
+    last_collected: i64,
+}
+
+/// Errors from collecting data from the AISHub API, so the caller can decide whether to back off, retry or skip the cycle
+#[derive(Debug)]
+enum CollectError {
+    /// Too frequent requests were made, holds the response body which may say how long to wait
+    RateLimited(String),
+    /// The request could not be made or the response could not be read.
+    /// transient is true for timeouts and connection errors, which are worth retrying right away
+    Network { message: String, transient: bool },
+    /// The response could not be parsed
+    Parse(String),
+    /// The response was cut off, e.g. by a dropped connection, which is worth retrying right away
+    Truncated(String),
+    /// AISHub returned an unsuccessful HTTP status, holds the start of the body as well
+    HttpStatus(u16, String),
+    /// The response body was empty
+    Empty,
+    /// ctrl+C was pressed before all requests were made
+    Interrupted,
+}
+
+impl CollectError {
+    /// Makes a network error from a failed request
+    fn from_request_error(context: &str, error: &reqwest::Error) -> CollectError {
+        // The reason an unexpected redirect was refused is only in the source of the error
+        let mut message = redact_api_key(error.to_string().as_str());
+        if error.is_redirect() {
+            match std::error::Error::source(error) {
+                Some(source) => message = std::format!("{}: {}", message, source),
+                None => {}
             }
+        }
+        return CollectError::Network {
+            message: std::format!("{}: {}", context, message),
+            transient: error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
         };
-        
-        // Create default VesselInfo struct
-        let mut vessel_info = VesselInfo::new();
+    }
 
-        // Fill in values that exist based on header order
-        match header_order[0] {
-            Some(index) => vessel_info.a = record[index].parse().unwrap(),
-            None => {}
+    /// Checks if the error is a network error, timeout or truncated response which is worth retrying right away
+    /// Too frequent requests and errors in the response are not transient
+    fn is_transient(&self) -> bool {
+        match self {
+            CollectError::Network { transient, .. } => return *transient,
+            CollectError::Truncated(_) => return true,
+            _ => return false,
         }
-        match header_order[1] {
-            Some(index) => vessel_info.b = record[index].parse().unwrap(),
-            None => {}
+    }
+}
+
+impl std::fmt::Display for CollectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CollectError::RateLimited(body) => return write!(f, "{}", body),
+            CollectError::Network { message, .. } => return write!(f, "{}", message),
+            CollectError::Parse(message) => return write!(f, "Error parsing response from AISHub API: {}", message),
+            CollectError::Truncated(message) => return write!(f, "{}", message),
+            CollectError::HttpStatus(status, snippet) => return write!(f, "AISHub API returned HTTP status {}: {}", status, snippet),
+            CollectError::Empty => return write!(f, "AISHub API returned an empty response"),
+            CollectError::Interrupted => return write!(f, "Stopped before all requests were made"),
         }
-        match header_order[2] {
-            Some(index) => vessel_info.c = record[index].parse().unwrap(),
-            None => {}
+    }
+}
+
+impl std::error::Error for CollectError {}
+
+
+// Functions
+// --------------------------------------------------------------------------------------
+
+/// Gets the path of the settings file, settings.toml if it exists and settings.json does not, otherwise settings.json
+fn get_settings_path() -> &'static str {
+    if !std::path::Path::new(SETTINGS_FILE_JSON).exists() && std::path::Path::new(SETTINGS_FILE_TOML).exists() {
+        return SETTINGS_FILE_TOML;
+    }
+    return SETTINGS_FILE_JSON;
+}
+
+/// Checks if a settings file is a TOML file, otherwise it is read and written as JSON
+fn is_toml_file(path: &str) -> bool {
+    return std::path::Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"));
+}
+
+/// Gets settings from settings file, either settings.json or settings.toml
+/// API key, loop interval (in minutes)
+/// Returns an error if the file can not be read or parsed. The startup load stops the program on an error, the reloads during a run keep the previous settings
+fn get_settings() -> Result<Settings, io::Error> {
+    // Parse settings file
+    let path = get_settings_path();
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, std::format!("Error reading {} file: {}", path, e)));
         }
-        match header_order[3] {
-            Some(index) => vessel_info.callsign = record[index].to_string(),
-            None => {}
+    };
+    // Parse errors are returned instead of panicking, so a half written file read while it is being edited does not stop the program
+    let parsed: Result<Settings, String> = if is_toml_file(path) {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    };
+    let mut settings = match parsed {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Error parsing {} file: {}", path, e)));
         }
-        match header_order[4] {
-            Some(index) => vessel_info.cog = record[index].parse().unwrap(),
-            None => {}
+    };
+
+    // Use the API key from the environment variable if set, it takes precedence over the settings file
+    match std::env::var(API_KEY_ENV_VAR) {
+        Ok(api_key) if !api_key.trim().is_empty() => settings.api_key_in_file = Some(std::mem::replace(&mut settings.api_key, api_key.trim().to_string())),
+        _ => {}
+    }
+
+    // Read bbox into the separate bounds, it is an error to set both so it is clear which is used
+    match &settings.bbox {
+        Some(bbox) => {
+            if settings.lat_min.is_some() || settings.lat_max.is_some() || settings.lon_min.is_some() || settings.lon_max.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid bbox in {} file: bbox can not be used together with lat_min, lat_max, lon_min or lon_max, remove one of them", path)));
+            }
+            let bounds: Vec<f64> = match bbox.split(',').map(|v| v.trim().parse::<f64>()).collect() {
+                Ok(b) => b,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid bbox in {} file: {}: {}. Use \"lat_min,lon_min,lat_max,lon_max\"", path, bbox, e))),
+            };
+            if bounds.len() != 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid bbox in {} file: {}. Use \"lat_min,lon_min,lat_max,lon_max\"", path, bbox)));
+            }
+            settings.lat_min = Some(bounds[0]);
+            settings.lon_min = Some(bounds[1]);
+            settings.lat_max = Some(bounds[2]);
+            settings.lon_max = Some(bounds[3]);
+        },
+        None => {}
+    }
+
+    // Check base_url is a valid URL so the requests can be made
+    match &settings.base_url {
+        Some(base_url) => {
+            match reqwest::Url::parse(base_url) {
+                Ok(_) => {},
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid base_url in {} file: {}: {}", path, base_url, e))),
+            }
+        },
+        None => {}
+    }
+
+    // Check path_template gives each vessel its own CSV file
+    match &settings.path_template {
+        Some(template) => {
+            if !template.ends_with(".csv") || !template.contains("{id}") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid path_template in {} file: {}. It must end with .csv and contain {{id}} so each vessel has its own file", path, template)));
+            }
+            if settings.partition_by_date {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid path_template in {} file: path_template can not be used together with partition_by_date, use {{date}} in the template instead", path)));
+            }
+        },
+        None => {}
+    }
+
+    // Check age_max can be read
+    match &settings.age_max {
+        Some(age_max) => {
+            match age_max.to_minutes() {
+                Ok(_) => {},
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid age_max in {} file: {}", path, e))),
+            }
+        },
+        None => {}
+    }
+
+    // Check log_level can be read and update the log level
+    let log_level = match settings.log_level.as_str() {
+        "quiet" => LOG_LEVEL_QUIET,
+        "normal" | "" => LOG_LEVEL_NORMAL,
+        "verbose" => LOG_LEVEL_VERBOSE,
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid log_level in {} file: {}. Use \"quiet\", \"normal\" or \"verbose\"", path, other))),
+    };
+    LOG_LEVEL.store(log_level, Ordering::SeqCst);
+
+    // Check quote_style can be read
+    match settings.quote_style.as_str() {
+        "necessary" | "always" | "never" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid quote_style in {} file: {}. Use \"necessary\", \"always\" or \"never\"", path, other))),
+    }
+
+    // Check the polygon has an area
+    match &settings.polygon {
+        Some(vertices) if vertices.len() < 3 => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid polygon in {} file: {} vertices given, at least 3 are needed", path, vertices.len()))),
+        _ => {}
+    }
+
+    // Check active_hours are hours of the day and not the same hour, which would be an empty or a whole day
+    match settings.active_hours {
+        Some((start, end)) if start > 23 || end > 23 || start == end => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid active_hours in {} file: [{}, {}]. Use two different hours from 0 to 23, e.g. [6, 22]", path, start, end)));
+        },
+        _ => {}
+    }
+
+    // Check id_priority can be read
+    match settings.id_priority.as_str() {
+        "imo" | "mmsi" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid id_priority in {} file: {}. Use \"imo\" or \"mmsi\"", path, other))),
+    }
+
+    // Check storage_format can be read
+    match settings.storage_format.as_str() {
+        "csv" | "parquet" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid storage_format in {} file: {}. Use \"csv\" or \"parquet\"", path, other))),
+    }
+
+    // Check the sinks are known and each is only listed once
+    for (index, sink) in settings.sinks.iter().enumerate() {
+        match sink.as_str() {
+            "csv" | "parquet" | "sqlite" => {},
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid sinks in {} file: {}. Use \"csv\", \"parquet\" or \"sqlite\"", path, other))),
         }
-        match header_order[5] {
-            Some(index) => vessel_info.d = record[index].parse().unwrap(),
-            None => {}
+        if settings.sinks[..index].contains(sink) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid sinks in {} file: {} is listed more than once", path, sink)));
         }
-        match header_order[6] {
-            Some(index) => vessel_info.dest = record[index].to_string(),
-            None => {}
+    }
+
+    // Check max_requests_per_hour allows at least one request
+    if settings.max_requests_per_hour == Some(0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid max_requests_per_hour in {} file: 0. Use at least 1", path)));
+    }
+
+    // Check filename_case can be read
+    match settings.filename_case.as_str() {
+        "upper" | "lower" | "keep" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid filename_case in {} file: {}. Use \"upper\", \"lower\" or \"keep\"", path, other))),
+    }
+
+    // Check on_suspect_position can be read
+    match settings.on_suspect_position.as_str() {
+        "skip" | "flag" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid on_suspect_position in {} file: {}. Use \"skip\" or \"flag\"", path, other))),
+    }
+
+    // Check on_write_error can be read
+    match settings.on_write_error.as_str() {
+        "skip" | "retry" | "pause" | "" => {},
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Invalid on_write_error in {} file: {}. Use \"skip\", \"retry\" or \"pause\"", path, other))),
+    }
+
+    // Return settings
+    return Ok(settings);
+}
+
+/// Checks if the log level is quiet, i.e. only errors and warnings are printed
+fn is_quiet() -> bool {
+    return LOG_LEVEL.load(Ordering::SeqCst) == LOG_LEVEL_QUIET;
+}
+
+/// Checks if the log level is verbose, i.e. details about the requests and parsing are printed
+fn is_verbose() -> bool {
+    return LOG_LEVEL.load(Ordering::SeqCst) == LOG_LEVEL_VERBOSE;
+}
+
+/// Checks if the API key is set, i.e. not empty and not the placeholder from --init
+fn is_api_key_set(api_key: &str) -> bool {
+    let api_key = api_key.trim();
+    return !api_key.is_empty() && api_key != API_KEY_PLACEHOLDER;
+}
+
+/// Replaces the API key in a URL, i.e. the value of each username parameter, with *** so the URL can be printed
+/// Also works on error messages with URLs in them, the key ends at the next &, whitespace, quote or closing bracket
+fn redact_api_key(text: &str) -> String {
+    let mut redacted = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("username=") {
+        let (before, after) = rest.split_at(start + "username=".len());
+        redacted.push_str(before);
+        redacted.push_str("***");
+        let key_length = after.find(|c: char| c == '&' || c.is_whitespace() || c == '"' || c == '\'' || c == ')').unwrap_or(after.len());
+        rest = &after[key_length..];
+    }
+    redacted.push_str(rest);
+    return redacted;
+}
+
+/// Sets the settings in the settings file, written as TOML if it is settings.toml
+fn set_settings(settings: &Settings) {
+    // Never write the API key from the environment variable to the file
+    let mut settings = settings.clone();
+    match settings.api_key_in_file.take() {
+        Some(api_key) => settings.api_key = api_key,
+        None => {}
+    }
+
+    // Keep the bounds read from bbox out of the file, it would no longer load with both set
+    if settings.bbox.is_some() {
+        settings.lat_min = None;
+        settings.lat_max = None;
+        settings.lon_min = None;
+        settings.lon_max = None;
+    }
+
+    // Serialize settings to the format of the settings file
+    let path = get_settings_path();
+    let contents = if is_toml_file(path) {
+        toml::to_string_pretty(&settings).expect("Error serializing settings to TOML")
+    } else {
+        serde_json::to_string_pretty(&settings).expect("Error serializing settings to JSON")
+    };
+
+    // Write settings to the settings file. Not being able to, e.g. when the disk is full, should not stop the program
+    match fs::write(path, contents) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Error writing settings to {} file: {}\nThe changes only apply until the program is restarted.", path, e);
         }
-        match header_order[7] {
-            Some(index) => vessel_info.draught = record[index].parse().unwrap(),
-            None => {}
+    };
+}
+
+/// Raises update_interval to MIN_UPDATE_INTERVAL if it is lower, e.g. 0 by mistake, which would make requests as fast as possible, and logs the correction.
+/// Only changes the settings in memory, the settings file is left as it is
+fn enforce_min_update_interval(settings: &mut Settings) {
+    if settings.update_interval < MIN_UPDATE_INTERVAL {
+        println!("Warning: update_interval is {} minute/s in the settings file, using the minimum of {} minute/s instead.", settings.update_interval, MIN_UPDATE_INTERVAL);
+        settings.update_interval = MIN_UPDATE_INTERVAL;
+    }
+}
+
+/// Sleeps until the next cycle should start
+/// Sleeps in small chunks and reloads the settings between them so changes to update_interval take effect right away
+/// A random number of seconds up to jitter_secs is added to the interval
+/// Returns early if ctrl+C is pressed
+fn sleep_until_next_cycle(settings: &mut Settings) {
+    // Init sleep start time and pick a random offset within jitter_secs
+    let sleep_start = std::time::Instant::now();
+    let mut outside_active_hours = false;
+    let jitter = random_up_to(settings.jitter_secs.unwrap_or(0));
+    if jitter > 0 && is_verbose() {
+        println!("Adding {} seconds of jitter to the update interval", jitter);
+    }
+
+    loop {
+        // Stop sleeping if ctrl+C was pressed
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return;
         }
-        match header_order[8] {
-            Some(index) => vessel_info.device = record[index].to_string(),
-            None => {}
+
+        // Update settings in case update_interval changed, errors are reported at the start of the next cycle
+        match get_settings() {
+            Ok(s) => *settings = s,
+            Err(_) => {},
+        };
+        settings.update_interval = settings.update_interval.max(MIN_UPDATE_INTERVAL);
+
+        // Stop sleeping if the interval is over. Outside active_hours keep sleeping until they start, or until inactive_interval is over if set
+        let interval = std::time::Duration::from_secs(settings.cycle_interval_secs() + jitter);
+        let elapsed = sleep_start.elapsed();
+        let mut remaining = interval.saturating_sub(elapsed);
+        match settings.active_hours {
+            Some(hours) if remaining.is_zero() => {
+                let now = time::UtcDateTime::now();
+                remaining = std::time::Duration::from_secs(seconds_until_active_hours(hours, now));
+                match settings.inactive_interval {
+                    Some(minutes) => remaining = remaining.min(std::time::Duration::from_secs(minutes as u64 * 60).saturating_sub(elapsed)),
+                    None => {}
+                }
+                if !remaining.is_zero() {
+                    SLEEPING_UNTIL.store((now + remaining).unix_timestamp(), Ordering::SeqCst);
+                }
+                if !remaining.is_zero() && !outside_active_hours && !is_quiet() {
+                    println!("Outside active_hours, the next cycle is at {} UTC.", format_datetime(now + remaining));
+                }
+                outside_active_hours = true;
+            },
+            _ => {}
         }
-        match header_order[9] {
-            Some(index) => vessel_info.eta = record[index].parse().unwrap(),
-            None => {}
+        if remaining.is_zero() {
+            return;
         }
-        match header_order[10] {
-            Some(index) => vessel_info.heading = record[index].parse().unwrap(),
-            None => {}
+
+        // Sleep for one chunk or the rest of the interval, whichever is shorter
+        let granularity = std::time::Duration::from_secs(settings.sleep_granularity.unwrap_or(SLEEP_GRANULARITY_DEFAULT).max(1));
+        std::thread::sleep(granularity.min(remaining));
+    }
+}
+
+/// Sleeps until active_hours start, or returns right away if they are not set or have started. Used before the first cycle
+/// Updates the settings while sleeping in case active_hours changed, and stops sleeping if ctrl+C is pressed
+fn sleep_until_active_hours(settings: &mut Settings) {
+    let mut logged = false;
+    loop {
+        // Stop sleeping if ctrl+C was pressed
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return;
         }
-        match header_order[11] {
-            Some(index) => vessel_info.imo = record[index].parse().unwrap(),
-            None => {}
+
+        // Update settings in case active_hours changed, errors are reported at the start of the next cycle
+        match get_settings() {
+            Ok(s) => *settings = s,
+            Err(_) => {},
+        };
+
+        // Stop sleeping if active_hours have started
+        let now = time::UtcDateTime::now();
+        let remaining = match settings.active_hours {
+            Some(hours) => std::time::Duration::from_secs(seconds_until_active_hours(hours, now)),
+            None => return,
+        };
+        if remaining.is_zero() {
+            return;
         }
-        match header_order[12] {
-            Some(index) => vessel_info.latitude = record[index].to_string(),
-            None => {}
+        SLEEPING_UNTIL.store((now + remaining).unix_timestamp(), Ordering::SeqCst);
+        if !logged {
+            println!("Outside active_hours, the first cycle is at {} UTC.", format_datetime(now + remaining));
+            logged = true;
         }
-        match header_order[13] {
-            Some(index) => vessel_info.longitude = record[index].to_string(),
-            None => {}
+
+        // Sleep for one chunk or until active_hours start, whichever is shorter
+        let granularity = std::time::Duration::from_secs(settings.sleep_granularity.unwrap_or(SLEEP_GRANULARITY_DEFAULT).max(1));
+        std::thread::sleep(granularity.min(remaining));
+    }
+}
+
+/// Gets the number of seconds from now until active_hours (start, end) start, 0 if now is inside them.
+/// They run from the start of the start hour to the start of the end hour in UTC, past midnight if the start hour is after the end hour, e.g. (22, 6)
+fn seconds_until_active_hours(active_hours: (u8, u8), now: time::UtcDateTime) -> u64 {
+    let (start, end) = active_hours;
+    let hour = now.hour();
+    let inside = if start < end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    };
+    if inside {
+        return 0;
+    }
+
+    // Seconds until the start hour, today or tomorrow
+    let seconds_of_day = hour as u64 * 3600 + now.minute() as u64 * 60 + now.second() as u64;
+    return (start as u64 * 3600 + 86400 - seconds_of_day) % 86400;
+}
+
+/// Gets a random number from 0 up to and including max
+/// Uses the random keys std makes for each HashMap, which is good enough to spread out requests without adding a dependency
+fn random_up_to(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    let random = std::hash::BuildHasher::hash_one(&std::collections::hash_map::RandomState::new(), std::time::SystemTime::now());
+    return random % (max + 1);
+}
+
+/// Makes a settings.json file with default settings and a ships.csv file with headers and a placeholder row for first time setup
+/// Existing files are never overwritten
+fn init_files() {
+    // Make settings.json, unless there already is a settings file
+    if std::path::Path::new(get_settings_path()).exists() {
+        println!("{} already exists, leaving it as it is.", get_settings_path());
+    } else {
+        set_settings(&Settings { api_key: API_KEY_PLACEHOLDER.to_string(), ..Settings::default() });
+        println!("Created settings.json. Replace {} with your AISHub API key and set update_interval (in minutes) before starting.", API_KEY_PLACEHOLDER);
+    }
+
+    // Make ships.csv
+    if std::path::Path::new("ships.csv").exists() {
+        println!("ships.csv already exists, leaving it as it is.");
+    } else {
+        match fs::write("ships.csv", "imo;mmsi;notes\n;;Replace this line with the imo and/or mmsi number of a ship to monitor, one ship per line\n") {
+            Ok(_) => println!("Created ships.csv. Add the imo and/or mmsi numbers of the ships you wish to monitor, separated by semicommas. If both are given, imo is used."),
+            Err(e) => println!("Error creating ships.csv file: {}", e),
         }
-        match header_order[14] {
-            Some(index) => vessel_info.mmsi = record[index].parse().unwrap(),
-            None => {}
+    }
+}
+
+/// Locks the data folder with an OS advisory lock on LOCK_FILE so two instances never write to the same files at once
+/// The lock is held until the returned file is dropped, or the program stops even if it crashes
+/// Returns None and prints why if another instance holds the lock. If locking is not supported, e.g. on some network drives, a warning is printed and the program continues
+fn lock_data_folder() -> Option<fs::File> {
+    // Make data folder if it does not exist
+    match fs::create_dir_all("data") {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Error creating data folder: {}", e);
+            return None;
         }
-        match header_order[15] {
-            Some(index) => vessel_info.name = record[index].to_string(),
-            None => {}
+    }
+
+    // Open lock file, it is never removed so an old one left behind does not matter
+    let mut file = match fs::OpenOptions::new().create(true).truncate(false).write(true).open(LOCK_FILE) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Error opening lock file {}: {}", LOCK_FILE, e);
+            return None;
         }
-        match header_order[16] {
-            Some(index) => vessel_info.navstat = record[index].to_string(),
-            None => {}
+    };
+
+    // Take the lock without waiting
+    match file.try_lock() {
+        Ok(_) => {},
+        Err(fs::TryLockError::WouldBlock) => {
+            println!("Another instance of AISHub Data Collector is already using the data folder ({} is locked). Stop it before starting a new one, two instances would mix up each other's records.", LOCK_FILE);
+            return None;
+        },
+        Err(fs::TryLockError::Error(e)) => {
+            println!("Warning: could not lock {}, continuing without protection against a second instance: {}", LOCK_FILE, e);
+            return Some(file);
         }
-        match header_order[17] {
-            Some(index) => vessel_info.pac = record[index].parse().unwrap(),
-            None => {}
+    }
+
+    // Write the process id so the instance holding the lock can be found
+    match file.set_len(0).and_then(|_| write!(file, "{}", std::process::id())) {
+        Ok(_) => {},
+        Err(e) => println!("Error writing process id to {}: {}", LOCK_FILE, e),
+    }
+
+    // Return locked file
+    return Some(file);
+}
+
+/// Gets list of ships to monitor from ships.csv file
+/// Returns a tuple of two vectors and a map: (imo_numbers, mmsi_numbers, labels)
+/// If both numbers are provided the one set by id_priority is used, IMO by default
+/// Labels are read from an optional column with the header "label" and are keyed like get_ship_key, e.g. "IMO 1234567"
+fn get_list_of_ships(settings: &Settings) -> (Vec<String>, Vec<String>, std::collections::HashMap<String, String>) {
+    println!("Getting list of ships!");
+    let mut mmsi: Vec<String> = Vec::new();
+    let mut imo: Vec<String> = Vec::new();
+    let mut labels: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    // Numbers given next to the number that is used, e.g. "MMSI 123456789", which are not used because of id_priority
+    let mut unused_numbers: Vec<String> = Vec::new();
+
+    // Read ships.csv file
+    let contents = match fs::read_to_string("ships.csv") {
+        Ok(c) => c,
+        Err(e) => panic!("Error reading ships.csv file: {}", e),
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        // Allow variable number of fields per record
+        .flexible(true)
+        .has_headers(true)
+        .from_reader(contents.as_bytes());
+
+    // Find the label column if there is one
+    let label_index = match rdr.headers() {
+        Ok(headers) => headers.iter().position(|h| h.trim().eq_ignore_ascii_case("label")),
+        Err(_) => None,
+    };
+
+    // For each entry, if MMSI or IMO is provided, add to respective vector
+    for result in rdr.records() {
+        let record = match result {
+            Ok(r) => r,
+            Err(e) => {
+                // Notify user, quarantine and skip this record
+                println!("Error reading record from ships.csv file, ignoring and moving on.\nRecord ignored: {}", e);
+                quarantine_record("ships.csv", e.to_string().as_str(), get_raw_line(&contents, e.position().map(|p| p.line())).as_str());
+                continue;
+            }
+        };
+        let imo_number = record.get(0).unwrap_or_default().trim();
+        let mmsi_number = record.get(1).unwrap_or_default().trim();
+
+        // Pick the number to use, the one set by id_priority if both are provided
+        let use_imo = !imo_number.is_empty() && (mmsi_number.is_empty() || !settings.prefers_mmsi());
+        let (kind, number, other_kind, other_number) = if use_imo {
+            ("IMO", imo_number, "MMSI", mmsi_number)
+        } else {
+            ("MMSI", mmsi_number, "IMO", imo_number)
+        };
+        if number.is_empty() {
+            continue; // Skip if both are empty
         }
-        match header_order[18] {
-            Some(index) => vessel_info.rot = record[index].to_string(),
-            None => {}
+
+        // Add label
+        let label = label_index.and_then(|i| record.get(i)).unwrap_or_default().trim();
+        if !label.is_empty() {
+            labels.insert(format!("{} {}", kind, number), label.to_string());
         }
-        match header_order[19] {
-            Some(index) => vessel_info.sog = record[index].parse().unwrap(),
-            None => {}
+
+        // Add number
+        let numbers = if use_imo { &mut imo } else { &mut mmsi };
+        if numbers.iter().any(|n| n == number) {
+            println!("Warning: {} {} is listed more than once in ships.csv, ignoring the duplicate.", kind, number);
+            continue;
+        }
+        numbers.push(number.to_string());
+        if !other_number.is_empty() {
+            unused_numbers.push(format!("{} {}", other_kind, other_number));
+        }
+    }
+
+    // Warn if a ship is listed both by IMO and by MMSI since it would be collected twice
+    for (kind, other_kind, numbers) in [("IMO", "MMSI", &imo), ("MMSI", "IMO", &mmsi)] {
+        for number in numbers {
+            if unused_numbers.contains(&format!("{} {}", kind, number)) {
+                println!("Warning: {} {} is listed on its own and next to an {} number in ships.csv. The ship will be collected by both IMO and MMSI.", kind, number, other_kind);
+            }
+        }
+    }
+
+    // Return tuple of vectors and labels
+    return (imo, mmsi, labels);
+}
+
+/// Gets the key used for a vessel in the labels and the session summary, e.g. "IMO 1234567", or "MMSI 123456789" if it has no IMO number or id_priority is "mmsi"
+fn get_ship_key(vessel: &VesselInfo, settings: &Settings) -> String {
+    return make_ship_key(vessel.imo, vessel.mmsi, settings);
+}
+
+/// Makes the key of a ship from its IMO and MMSI numbers, see get_ship_key
+fn make_ship_key(imo: u64, mmsi: u64, settings: &Settings) -> String {
+    if imo != 0 && (mmsi == 0 || !settings.prefers_mmsi()) {
+        return format!("IMO {}", imo);
+    }
+    return format!("MMSI {}", mmsi);
+}
+
+/// Takes in a vector of strings and returns a single string with the delimiter between the values
+/// E.g. if the delimiter is a semicomma: ["123", "456", "789"] -> "123;456;789"
+fn vec_to_delimiter_separated_string(vec: &[String], delimiter: char) -> Option<String> {
+    // Return None if vector is empty
+    if vec.is_empty() {
+        return None;
+    }
+
+    // Loop through vector and build string
+    let mut result = String::new();
+    for (i, value) in vec.iter().enumerate() {
+        result.push_str(value);
+        if i < vec.len() - 1 {
+            result.push(delimiter); // Add delimiter if not the last value
+        }
+    }
+
+    return Some(result);
+}
+
+/// Splits the ships into batches of at most batch_size ships in total, one request is made per batch
+/// Each batch is a tuple of semicomma separated (imo, mmsi) numbers, None if the batch has no numbers of that kind
+/// If there are no ships at all a single batch with no numbers is returned so the bounding box alone is used
+fn make_ship_batches(imo_nums: &[String], mmsi_nums: &[String], batch_size: usize) -> Vec<(Option<String>, Option<String>)> {
+    // Combine ships, keeping track of which are IMO numbers
+    let ships: Vec<(bool, &String)> = imo_nums.iter().map(|n| (true, n)).chain(mmsi_nums.iter().map(|n| (false, n))).collect();
+
+    // No ships, one request without ship numbers
+    if ships.is_empty() {
+        return vec![(None, None)];
+    }
+
+    // Split into batches
+    let mut batches: Vec<(Option<String>, Option<String>)> = Vec::new();
+    for chunk in ships.chunks(batch_size.max(1)) {
+        let imo: Vec<String> = chunk.iter().filter(|(is_imo, _)| *is_imo).map(|(_, n)| n.to_string()).collect();
+        let mmsi: Vec<String> = chunk.iter().filter(|(is_imo, _)| !*is_imo).map(|(_, n)| n.to_string()).collect();
+        batches.push((vec_to_delimiter_separated_string(&imo, ';'), vec_to_delimiter_separated_string(&mmsi, ';')));
+    }
+
+    // Return batches
+    return batches;
+}
+
+/// Gets data from the AISHub API with one request per batch of ships and merges the results
+/// Waits batch_delay seconds between requests. If any request fails, including too frequent requests, no more requests are made.
+/// The records from the batches before the failed request are returned along with the error so they can still be saved, the error is only returned on its own if there are none or ctrl+C was pressed.
+/// The time spent fetching and parsing is added to timings
+fn get_data_in_batches(batches: &[(Option<String>, Option<String>)], api_key: &str, client: &reqwest::blocking::Client, settings: &Settings, timings: &mut CycleTimings) -> Result<(Vec<VesselInfo>, Option<CollectError>), CollectError> {
+    let mut data: Vec<VesselInfo> = Vec::new();
+
+    for (i, (imo, mmsi)) in batches.iter().enumerate() {
+        // Wait between requests
+        if i > 0 {
+            if !is_quiet() {
+                println!("Waiting before requesting batch {} of {}", i + 1, batches.len());
+            }
+            if !sleep_unless_shutdown(std::time::Duration::from_secs(settings.batch_delay.unwrap_or(BATCH_DELAY_DEFAULT))) {
+                return Err(CollectError::Interrupted);
+            }
+        }
+
+        // Make URL and get data, retrying transient errors up to retries_per_cycle times
+        let (lat_min, lat_max, lon_min, lon_max) = settings.bounding_box();
+        let url = match make_aishub_url(settings.base_url.as_deref().unwrap_or(AISHUB_BASE_URL_DEFAULT), api_key, settings.data_value_format, settings.output_format.as_str(), settings.compression, lat_min, lat_max, lon_min, lon_max, mmsi.as_deref(), imo.as_deref(), settings.age_max_minutes()) {
+            Ok(u) => u,
+            Err(e) => return Err(CollectError::Network { message: std::format!("Error making AISHub API URL: {}", e), transient: false }),
+        };
+        if is_verbose() {
+            println!("Requesting {}", redact_api_key(url.as_str()));
+        }
+        let retries = settings.retries_per_cycle.unwrap_or(0);
+        let mut attempt: u32 = 0;
+        loop {
+            match get_data_from_aishub_api(url.clone(), client, settings, timings) {
+                Ok(mut d) => {
+                    data.append(&mut d);
+                    break;
+                },
+                // An empty response has no records, the other batches are still requested
+                Err(CollectError::Empty) => {
+                    if !is_quiet() {
+                        println!("AISHub API returned an empty response, no records from this request.");
+                    }
+                    break;
+                },
+                Err(e) if e.is_transient() && attempt < retries => {
+                    attempt += 1;
+                    println!("Error getting data from AISHub API: {}\nRetrying in {} seconds (retry {} of {}).", e, RETRY_DELAY, attempt, retries);
+                    if !sleep_unless_shutdown(std::time::Duration::from_secs(RETRY_DELAY)) {
+                        return Err(CollectError::Interrupted);
+                    }
+                },
+                // Keep the records from the batches before this one
+                Err(e) if !data.is_empty() => return Ok((data, Some(e))),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Return merged data
+    return Ok((data, None));
+}
+
+/// Rests the API key that made too frequent requests if there are several, and notifies the webhook.
+/// With a single key the update interval or max_requests_per_hour has already been changed in the settings file when the response was read
+fn handle_rate_limited(body: &str, api_key: &str, settings: &Settings, client: &reqwest::blocking::Client, key_rotator: &mut KeyRotator, notifier: &mut WebhookNotifier) {
+    // With several API keys only the key that made too frequent requests rests, the update interval is left as it is
+    if settings.get_api_keys().len() > 1 {
+        let minutes = parse_interval_hint(body, None).unwrap_or(settings.update_interval + INTERVAL_DEFAULT_INCREMENT);
+        key_rotator.rest(api_key, minutes);
+        println!("Too frequent requests made to AISHub API with one of the API keys, not using it for {} minute/s.", minutes);
+        notifier.notify(settings, client, format!("Too frequent requests made to AISHub API with one of the API keys, it is resting for {} minute/s.", minutes).as_str());
+    } else if settings.max_requests_per_hour.is_some() {
+        notifier.notify(settings, client, "Too frequent requests made to AISHub API, max_requests_per_hour has been lowered.");
+    } else {
+        notifier.notify(settings, client, "Too frequent requests made to AISHub API, update interval has been increased.");
+    }
+}
+
+/// Gets data from the fallback source with one request per batch of ships, parsed the same way as the AISHub responses
+/// Waits batch_delay seconds between requests. If any request fails no more requests are made and the error is returned
+fn get_data_from_fallback(template: &str, batches: &[(Option<String>, Option<String>)], client: &reqwest::blocking::Client, settings: &Settings, timings: &mut CycleTimings) -> Result<Vec<VesselInfo>, CollectError> {
+    let mut data: Vec<VesselInfo> = Vec::new();
+
+    for (i, (imo, mmsi)) in batches.iter().enumerate() {
+        // Wait between requests
+        if i > 0 && !sleep_unless_shutdown(std::time::Duration::from_secs(settings.batch_delay.unwrap_or(BATCH_DELAY_DEFAULT))) {
+            return Err(CollectError::Interrupted);
+        }
+
+        // Make URL and get data
+        let url = make_fallback_url(template, settings, imo.as_deref(), mmsi.as_deref());
+        if is_verbose() {
+            println!("Requesting {} from the fallback source", redact_api_key(url.as_str()));
+        }
+        // Only the response is parsed, too frequent requests to the fallback source do not change the settings file and the response is not saved as an AISHub response
+        let (body, status, _) = fetch_response(url, "the fallback source", client, timings)?;
+        if body.starts_with("Too frequent requests") {
+            return Err(CollectError::RateLimited(body));
+        }
+        match parse_fetched_response(&body, status, settings, timings) {
+            Ok(mut d) => data.append(&mut d),
+            Err(CollectError::Empty) => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Return merged data
+    return Ok(data);
+}
+
+/// Makes the URL for a request to the fallback source by filling in the placeholders in fallback_url_template
+/// Placeholders for values that are not set are replaced with nothing.
+/// The values are percent-encoded like the parameters in make_aishub_url, e.g. the commas between several numbers become %2C
+fn make_fallback_url(template: &str, settings: &Settings, imo: Option<&str>, mmsi: Option<&str>) -> String {
+    let (lat_min, lat_max, lon_min, lon_max) = settings.bounding_box();
+    let encode = |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+    let format_value = |value: Option<f64>| encode(value.map(|v| v.to_string()).unwrap_or_default().as_str());
+    return template
+        .replace("{imo}", encode(imo.unwrap_or_default()).as_str())
+        .replace("{mmsi}", encode(mmsi.unwrap_or_default()).as_str())
+        .replace("{lat_min}", format_value(lat_min).as_str())
+        .replace("{lat_max}", format_value(lat_max).as_str())
+        .replace("{lon_min}", format_value(lon_min).as_str())
+        .replace("{lon_max}", format_value(lon_max).as_str());
+}
+
+/// Sleeps for the given duration in small chunks, returning early if ctrl+C is pressed
+/// Returns false if the sleep was cut short by ctrl+C
+fn sleep_unless_shutdown(duration: std::time::Duration) -> bool {
+    let sleep_start = std::time::Instant::now();
+    while sleep_start.elapsed() < duration {
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return false;
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1).min(duration.saturating_sub(sleep_start.elapsed())));
+    }
+    return true;
+}
+
+/// Makes the URL for the AISHub API request
+/// Based on https://www.aishub.net/api
+/// base_url is the address of the API without parameters, normally AISHUB_BASE_URL_DEFAULT
+/// All optional parameters may be None, then only the required username, format, output and compress parameters are sent
+/// and AISHub returns every vessel the account has access to.
+/// The parameters are percent-encoded, so e.g. an API key with & or spaces in it can not break the query. Returns an error if base_url is not a valid URL
+fn make_aishub_url(base_url: &str, api_key: &str, data_value_format: u8, output_format: &str, compression: u8, lat_min: Option<f64>, lat_max: Option<f64>, lon_min: Option<f64>, lon_max: Option<f64>, mmsi: Option<&str>, imo: Option<&str>, age_max: Option<u64>) -> Result<String, Box<dyn std::error::Error>> {
+    let mut url = reqwest::Url::parse(base_url)?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("username", api_key);
+        query.append_pair("format", data_value_format.to_string().as_str());
+        query.append_pair("output", output_format);
+        query.append_pair("compress", compression.to_string().as_str());
+
+        // Add optional parameters
+        let optional_parameters = [
+            ("latmin", lat_min.map(|v| v.to_string())),
+            ("latmax", lat_max.map(|v| v.to_string())),
+            ("lonmin", lon_min.map(|v| v.to_string())),
+            ("lonmax", lon_max.map(|v| v.to_string())),
+            ("mmsi", mmsi.map(|v| v.to_string())),
+            ("imo", imo.map(|v| v.to_string())),
+            ("interval", age_max.map(|v| v.to_string())),
+        ];
+        for (name, value) in optional_parameters {
+            match value {
+                Some(value) => {
+                    query.append_pair(name, value.as_str());
+                },
+                None => {}
+            }
+        }
+    }
+
+    // Return URL
+    return Ok(url.to_string());
+}
+
+/// Function that fetches data from AISHub API given a URL
+/// Assumes only 1 data point is returned per ship
+/// Too frequent requests increase the update interval or lower the request budget in the settings file, and the raw response is saved if set
+/// The time spent fetching and parsing is added to timings
+fn get_data_from_aishub_api(url: String, client: &reqwest::blocking::Client, settings: &Settings, timings: &mut CycleTimings) -> Result<Vec<VesselInfo>, CollectError> {
+    // Get the response
+    let (body, status, retry_after) = fetch_response(url, "AISHub API", client, timings)?;
+
+    // Save the raw response if a directory is set
+    match &settings.save_raw_responses {
+        Some(dir) => save_raw_response(&body, dir, settings.output_format.as_str()),
+        None => {}
+    }
+
+    // If too frequent requests are made, increase the update interval and return error
+    // With several API keys the interval is left as it is and only the key rests, see KeyRotator
+    if body.starts_with("Too frequent requests") && settings.get_api_keys().len() > 1 {
+        return Err(CollectError::RateLimited(body));
+    }
+    if body.starts_with("Too frequent requests") && settings.max_requests_per_hour.is_some() {
+        lower_request_budget(parse_interval_hint(&body, retry_after.as_deref()), settings);
+        return Err(CollectError::RateLimited(body));
+    }
+    if body.starts_with("Too frequent requests") {
+        // Do not increase the interval past max_update_interval
+        let max_interval = settings.max_update_interval.unwrap_or(u32::MAX);
+        if settings.update_interval >= max_interval {
+            println!("WARNING: Too frequent requests made to AISHub API but the update interval is already at max_update_interval ({} minute/s), not increasing it further. Check that no other program is using the same API key.", max_interval);
+            return Err(CollectError::RateLimited(body));
+        }
+
+        let mut settings_modified = settings.clone();
+        // Use the interval AISHub asks for if it gives one and it is bigger than the current interval, otherwise increase by the default increment
+        match parse_interval_hint(&body, retry_after.as_deref()) {
+            Some(hint) if hint > settings.update_interval => {
+                settings_modified.update_interval = hint.min(max_interval);
+                println!("Too frequent requests made to AISHub API. AISHub asked for at least {} minute/s between requests, setting update interval in settings to {} minute/s.", hint, settings_modified.update_interval);
+            },
+            _ => {
+                settings_modified.update_interval = (settings.update_interval + INTERVAL_DEFAULT_INCREMENT).min(max_interval);
+                println!("Too frequent requests made to AISHub API. Increasing update interval in settings by {} minute. Please check your update interval and make sure it is big enough.", INTERVAL_DEFAULT_INCREMENT);
+            }
+        }
+        if settings_modified.update_interval == max_interval {
+            println!("WARNING: The update interval has reached max_update_interval ({} minute/s) and will not be increased further.", max_interval);
+        }
+        set_settings(&settings_modified);
+        return Err(CollectError::RateLimited(body));
+    }
+
+    // Parse the response
+    return parse_fetched_response(&body, status, settings, timings);
+}
+
+/// Makes a request to a source, e.g. "AISHub API", and gets the response body, HTTP status and Retry-After header, without looking at the body
+/// The time spent fetching is added to timings
+fn fetch_response(url: String, source: &str, client: &reqwest::blocking::Client, timings: &mut CycleTimings) -> Result<(String, reqwest::StatusCode, Option<String>), CollectError> {
+    let fetch_start = std::time::Instant::now();
+
+    // Get the result of the request, along with the status and the Retry-After header in case too frequent requests were made
+    let response = match client.get(url).send() {
+        Ok(response) => {
+            let status = response.status();
+            let retry_after = response.headers().get("Retry-After").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+            match response.bytes() {
+                Ok(bytes) => {
+                    if is_verbose() {
+                        println!("Response: HTTP status {}, {} bytes", status, bytes.len());
+                    }
+                    (decode_response(&bytes), status, retry_after)
+                },
+                Err(e) => {
+                    return Err(CollectError::from_request_error("Error reading response text", &e));
+                }
+            }
+        },
+        Err(e) => {
+            return Err(CollectError::from_request_error(format!("Error making request to {}", source).as_str(), &e));
+        }
+    };
+    timings.fetch += fetch_start.elapsed();
+
+    // Return response
+    return Ok(response);
+}
+
+/// Parses a fetched response body into records, with no side effects, so it can be used for AISHub and the fallback source
+/// Returns an error with the status and the start of the body if the request was not successful, so error pages are not parsed as data.
+/// The time spent parsing is added to timings
+fn parse_fetched_response(body: &str, status: reqwest::StatusCode, settings: &Settings, timings: &mut CycleTimings) -> Result<Vec<VesselInfo>, CollectError> {
+    if !status.is_success() {
+        let snippet: String = body.chars().take(ERROR_BODY_SNIPPET_LENGTH).collect();
+        return Err(CollectError::HttpStatus(status.as_u16(), snippet.trim().to_string()));
+    }
+
+    // Nothing to parse
+    if body.trim().is_empty() {
+        return Err(CollectError::Empty);
+    }
+
+    // Parse the response
+    let parse_start = std::time::Instant::now();
+    let mut data = match parse_response(body, settings.output_format.as_str(), settings.data_value_format) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(CollectError::Truncated(e.to_string())),
+        Err(e) => return Err(CollectError::Parse(e.to_string())),
+    };
+
+    // Convert SOG and COG to knots and degrees if set
+    if settings.normalize_sog_cog {
+        normalize_sog_cog(&mut data, settings.data_value_format);
+    }
+
+    // Clean up vessel names if set
+    if settings.normalize_names {
+        normalize_names(&mut data, settings.uppercase_names);
+    }
+    timings.parse += parse_start.elapsed();
+    if is_verbose() {
+        println!("Parsed {} records from the response", data.len());
+    }
+
+    // Return the data
+    return Ok(data);
+}
+
+/// Converts SOG to knots and COG to degrees, whichever data value format they were requested in
+/// AIS format (data_value_format 0) values are divided by 10. "Not available" values (SOG 102.3 knots or more, COG 360 degrees or more) become None
+fn normalize_sog_cog(data: &mut [VesselInfo], data_value_format: u8) {
+    let scale = if data_value_format == 0 { 10.0 } else { 1.0 };
+    for vessel in data {
+        vessel.sog = vessel.sog.map(|v| v / scale).filter(|v| *v < 102.3);
+        vessel.cog = vessel.cog.map(|v| v / scale).filter(|v| *v < 360.0);
+    }
+}
+
+/// Removes leading and trailing spaces from vessel names and collapses spaces inside them to one, e.g. " SEA  STAR   " -> "SEA STAR", and uppercases them if set
+fn normalize_names(data: &mut [VesselInfo], uppercase: bool) {
+    for vessel in data {
+        vessel.name = normalize_name(vessel.name.as_str(), uppercase);
+    }
+}
+
+/// Normalizes a single vessel name, see normalize_names
+fn normalize_name(name: &str, uppercase: bool) -> String {
+    let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if uppercase {
+        return name.to_uppercase();
+    }
+    return name;
+}
+
+/// Decodes a response body as UTF-8, replacing invalid bytes with � instead of failing, and strips a leading byte order mark
+/// so a single odd character in a vessel name does not fail the whole response or the header matching
+fn decode_response(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    return text.strip_prefix('\u{feff}').unwrap_or(&text).to_string();
+}
+
+/// Lowers max_requests_per_hour in the settings file after too frequent requests instead of increasing update_interval.
+/// Lowers it to one request per hint_minutes if AISHub said how long to wait and that is lower, otherwise by REQUEST_BUDGET_DECREASE_PERCENT percent,
+/// but never so far that the time between cycles would be longer than max_update_interval
+fn lower_request_budget(hint_minutes: Option<u32>, settings: &Settings) {
+    let budget = settings.max_requests_per_hour.unwrap_or(1);
+
+    // Lowest budget that keeps the time between cycles within max_update_interval
+    let min_budget = match settings.max_update_interval {
+        Some(max_interval) => (REQUESTS_PER_CYCLE.load(Ordering::SeqCst) * 60).div_ceil(max_interval.max(1) as u64) as u32,
+        None => 1,
+    }.max(1);
+    if budget <= min_budget {
+        println!("WARNING: Too frequent requests made to AISHub API but max_requests_per_hour is already at {}, the lowest max_update_interval allows. Check that no other program is using the same API key.", budget);
+        return;
+    }
+
+    // Use the interval AISHub asks for if it gives one, otherwise lower the budget by the default percentage
+    let mut settings_modified = settings.clone();
+    let new_budget = match hint_minutes {
+        Some(hint) if 60 / hint.max(1) < budget => 60 / hint.max(1),
+        _ => budget * (100 - REQUEST_BUDGET_DECREASE_PERCENT) / 100,
+    };
+    settings_modified.max_requests_per_hour = Some(new_budget.min(budget - 1).max(min_budget));
+    println!("Too frequent requests made to AISHub API. Lowering max_requests_per_hour in settings from {} to {}.", budget, settings_modified.max_requests_per_hour.unwrap_or_default());
+    set_settings(&settings_modified);
+}
+
+/// Gets the minimum interval in minutes AISHub asks for after too frequent requests, rounded up
+/// Uses the Retry-After header (in seconds) if given, otherwise looks for a number followed by seconds, minutes or hours in the body
+/// Returns None if no hint is found
+fn parse_interval_hint(body: &str, retry_after: Option<&str>) -> Option<u32> {
+    // Retry-After header in seconds
+    match retry_after.and_then(|v| v.trim().parse::<u64>().ok()) {
+        Some(seconds) => return Some(seconds.div_ceil(60).max(1) as u32),
+        None => {}
+    }
+
+    // Look for e.g. "2 minutes" or "90 seconds" in the body
+    let words: Vec<&str> = body.split_whitespace().collect();
+    for pair in words.windows(2) {
+        let number: f64 = match pair[0].trim_matches(|c: char| !c.is_ascii_digit() && c != '.').parse() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let unit = pair[1].to_lowercase();
+        let minutes = if unit.starts_with("sec") {
+            number / 60.0
+        } else if unit.starts_with("min") {
+            number
+        } else if unit.starts_with("hour") {
+            number * 60.0
+        } else {
+            continue;
+        };
+        return Some(minutes.ceil().max(1.0) as u32);
+    }
+
+    // No hint found
+    return None;
+}
+
+/// Parses a response body from the AISHub API in the given output format ("csv" or "xml") and data value format
+fn parse_response(body: &str, output_format: &str, data_value_format: u8) -> Result<Vec<VesselInfo>, io::Error> {
+    // XML responses are parsed separately
+    if output_format == "xml" {
+        return parse_xml_response(body);
+    }
+    return parse_csv_response(body, data_value_format);
+}
+
+/// Parses a response from the AISHub API in CSV format, without making any requests
+/// Columns are matched by header name with get_header_order so they can be in any order.
+/// Values are kept as they are in the response, in the data_value_format they were requested in, SOG and COG are normalized afterwards if set.
+/// The data_value_format is only used to note SOG and COG values that are "not available" in verbose mode
+fn parse_csv_response(body: &str, data_value_format: u8) -> Result<Vec<VesselInfo>, io::Error> {
+    // A quoted field that is never closed, e.g. a vessel name, means the response was cut off inside it. Escaped quotes are doubled so a complete response has an even number of quotes
+    if body.bytes().filter(|b| *b == b'"').count() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Response from AISHub API appears to be truncated, a quoted field is not closed"));
+    }
+
+    // Get CSV reader from body
+    let mut rdr = csv::Reader::from_reader(body.as_bytes());
+
+    // Get order of headers
+    let headers = match rdr.headers() {
+        Ok(h) => h.clone(),
+        Err(e) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Error reading headers from CSV response: {}", e)));
+        }
+    };
+    let header_order = get_header_order(&headers);
+    print_unknown_headers(&headers);
+    if is_verbose() {
+        println!("CSV response columns: {}", headers.iter().collect::<Vec<&str>>().join(", "));
+        for (i, header) in STANDARD_COLUMNS.iter().enumerate() {
+            if header_order[i].is_none() {
+                println!("Note: {} is not in the response, using the default value", header);
+            }
+        }
+    }
+
+    // Read all records so the last one can be checked before anything is parsed
+    let records: Vec<Result<csv::StringRecord, csv::Error>> = rdr.records().collect();
+
+    // If the last record has the wrong number of fields the response was most likely cut off mid-line.
+    // Treat it as a transient error so nothing from this response is stored and the request is retried, or made again next cycle
+    match records.last() {
+        Some(Err(e)) => {
+            match e.kind() {
+                csv::ErrorKind::UnequalLengths { .. } => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, std::format!("Response from AISHub API appears to be truncated, last record has the wrong number of fields: {}", e)));
+                },
+                _ => {}
+            }
+        },
+        _ => {}
+    }
+
+    // Init empty vector to hold data
+    let mut data: Vec<VesselInfo> = Vec::new();
+
+    // Loop through each line of the response body, append each data point to data vector
+    for result in records {
+        let record = match result {
+            Ok(r) => r,
+            Err(e) => {
+                // Notify user, quarantine and skip this record
+                println!("Error reading record from CSV response, ignoring and moving on.\nRecord ignored: {}", e);
+                quarantine_record("AISHub API", e.to_string().as_str(), get_raw_line(body, e.position().map(|p| p.line())).as_str());
+                continue;
+            }
+        };
+        
+        // Append to data vector, quarantining records with values that can not be read
+        let vessel = match record_to_vessel_info(&record, &header_order) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error reading record from CSV response, ignoring and moving on.\nRecord ignored: {}", e);
+                quarantine_record("AISHub API", e.as_str(), get_raw_line(body, record.position().map(|p| p.line())).as_str());
+                continue;
+            }
+        };
+        if is_verbose() {
+            // AIS format values are in 1/10 knot and 1/10 degree
+            let scale = if data_value_format == 0 { 10.0 } else { 1.0 };
+            match vessel.cog {
+                Some(cog) if cog / scale >= 360.0 => println!("Note: COG of MMSI {} is not available ({})", vessel.mmsi, cog),
+                Some(_) => {},
+                None => println!("Note: could not read COG of MMSI {}, storing it as empty", vessel.mmsi),
+            }
+            match vessel.sog {
+                Some(sog) if sog / scale >= 102.3 => println!("Note: SOG of MMSI {} is not available ({})", vessel.mmsi, sog),
+                Some(_) => {},
+                None => println!("Note: could not read SOG of MMSI {}, storing it as empty", vessel.mmsi),
+            }
+        }
+        data.push(vessel);
+    }
+
+    // Return the data vector
+    return Ok(data);
+}
+
+/// Makes a VesselInfo struct from a record given the header order from get_header_order
+/// Values are looked up by column name so the fields can not get out of step with STANDARD_COLUMNS.
+/// Values that are not in the record or are empty keep their default unknown values.
+/// Returns an error naming the column if a number can not be read, e.g. a decimal number in an integer column, so the record can be quarantined
+fn record_to_vessel_info(record: &csv::StringRecord, header_order: &[Option<usize>]) -> Result<VesselInfo, String> {
+    // Create default VesselInfo struct
+    let mut vessel_info = VesselInfo::new();
+
+    // Fill in values that exist based on header order
+    match parse_field(record, header_order, "A")? {
+        Some(value) => vessel_info.a = value,
+        None => {}
+    }
+    match parse_field(record, header_order, "B")? {
+        Some(value) => vessel_info.b = value,
+        None => {}
+    }
+    match parse_field(record, header_order, "C")? {
+        Some(value) => vessel_info.c = value,
+        None => {}
+    }
+    match get_field(record, header_order, "CALLSIGN") {
+        Some(value) => vessel_info.callsign = value.to_string(),
+        None => {}
+    }
+    match get_field(record, header_order, "COG") {
+        Some(value) => {
+            vessel_info.cog = value.parse().ok();
+            vessel_info.raw_cog = value.to_string();
+        },
+        None => {}
+    }
+    match parse_field(record, header_order, "D")? {
+        Some(value) => vessel_info.d = value,
+        None => {}
+    }
+    match get_field(record, header_order, "DEST") {
+        Some(value) => vessel_info.dest = value.to_string(),
+        None => {}
+    }
+    match parse_field(record, header_order, "DRAUGHT")? {
+        Some(value) => vessel_info.draught = value,
+        None => {}
+    }
+    match get_field(record, header_order, "DEVICE") {
+        Some(value) => vessel_info.device = value.to_string(),
+        None => {}
+    }
+    match parse_field(record, header_order, "ETA")? {
+        Some(value) => vessel_info.eta = value,
+        None => {}
+    }
+    match parse_field(record, header_order, "HEADING")? {
+        Some(value) => vessel_info.heading = value,
+        None => {}
+    }
+    match parse_field(record, header_order, "IMO")? {
+        Some(value) => vessel_info.imo = value,
+        None => {}
+    }
+    match get_field(record, header_order, "LATITUDE") {
+        Some(value) => vessel_info.latitude = value.to_string(),
+        None => {}
+    }
+    match get_field(record, header_order, "LONGITUDE") {
+        Some(value) => vessel_info.longitude = value.to_string(),
+        None => {}
+    }
+    match parse_field(record, header_order, "MMSI")? {
+        Some(value) => vessel_info.mmsi = value,
+        None => {}
+    }
+    match get_field(record, header_order, "NAME") {
+        Some(value) => {
+            vessel_info.name = value.to_string();
+            vessel_info.raw_name = value.to_string();
+        },
+        None => {}
+    }
+    match get_field(record, header_order, "NAVSTAT") {
+        Some(value) => vessel_info.navstat = value.to_string(),
+        None => {}
+    }
+    match parse_field(record, header_order, "PAC")? {
+        Some(value) => vessel_info.pac = value,
+        None => {}
+    }
+    match get_field(record, header_order, "ROT") {
+        Some(value) => vessel_info.rot = value.to_string(),
+        None => {}
+    }
+    match get_field(record, header_order, "SOG") {
+        Some(value) => {
+            vessel_info.sog = value.parse().ok();
+            vessel_info.raw_sog = value.to_string();
+        },
+        None => {}
+    }
+    match parse_field(record, header_order, "TSTAMP")? {
+        Some(value) => vessel_info.timestamp = value,
+        None => {}
+    }
+    match parse_field(record, header_order, "TYPE")? {
+        Some(value) => vessel_info.vessel_type = value,
+        None => {}
+    }
+
+    // Return vessel info
+    return Ok(vessel_info);
+}
+
+/// Parses the value of a numeric standard column from a record given the header order from get_header_order
+/// Returns None if the column is not in the record or is empty, and an error naming the column if the value can not be read
+fn parse_field<T: std::str::FromStr>(record: &csv::StringRecord, header_order: &[Option<usize>], column: &str) -> Result<Option<T>, String> {
+    match get_field(record, header_order, column).map(|value| value.trim()) {
+        Some("") | None => return Ok(None),
+        Some(value) => match value.parse() {
+            Ok(v) => return Ok(Some(v)),
+            Err(_) => return Err(format!("Invalid {} value: {}", column, value)),
+        },
+    }
+}
+
+/// Gets the value of a standard column from a record given the header order from get_header_order
+/// Returns None if the column is not in the record
+fn get_field<'r>(record: &'r csv::StringRecord, header_order: &[Option<usize>], column: &str) -> Option<&'r str> {
+    let position = STANDARD_COLUMNS.iter().position(|c| *c == column)?;
+    return record.get((*header_order.get(position)?)?);
+}
+
+/// Parses a response from the AISHub API in XML format
+/// Each vessel element is mapped the same way as a CSV record so the result is the same as for a CSV response.
+/// The record of each vessel is made from its own attributes in STANDARD_COLUMNS order, so vessels with different attributes are all read correctly.
+/// If the leading status element reports an error, or its record count does not match the number of vessels, an error is returned
+fn parse_xml_response(body: &str) -> Result<Vec<VesselInfo>, io::Error> {
+    let mut reader = quick_xml::Reader::from_str(body);
+
+    // Init header order, unknown attributes already reported, expected record count and data vector
+    // Records are made in STANDARD_COLUMNS order so every column is at its own position
+    let header_order: Vec<Option<usize>> = (0..STANDARD_COLUMNS.len()).map(Some).collect();
+    let mut unknown_attributes: Vec<String> = Vec::new();
+    let mut expected_records: Option<usize> = None;
+    let mut data: Vec<VesselInfo> = Vec::new();
+    // Depth of open elements, used to detect a truncated response
+    let mut depth: usize = 0;
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(e) => e,
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Error reading XML response at position {}: {}", reader.error_position(), e)));
+            }
+        };
+
+        // Get element, or stop at end of response
+        let (element, is_empty) = match event {
+            quick_xml::events::Event::Start(e) => (e, false),
+            quick_xml::events::Event::Empty(e) => (e, true),
+            quick_xml::events::Event::End(_) => {
+                depth = depth.saturating_sub(1);
+                continue;
+            },
+            quick_xml::events::Event::Eof => break,
+            _ => continue,
+        };
+        if !is_empty {
+            depth += 1;
+        }
+
+        // Get the attributes of the element
+        let mut attributes: Vec<(String, String)> = Vec::new();
+        for attribute in element.attributes() {
+            let attribute = match attribute {
+                Ok(a) => a,
+                Err(e) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Error reading XML attribute: {}", e)));
+                }
+            };
+            let value = match attribute.normalized_value(quick_xml::XmlVersion::Implicit1_0) {
+                Ok(v) => v.to_string(),
+                Err(e) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, std::format!("Error reading XML attribute value: {}", e)));
+                }
+            };
+            attributes.push((attribute.key.into_inner().to_uppercase(), value));
+        }
+
+        // Vessel elements are mapped like CSV records
+        if element.name().into_inner().eq_ignore_ascii_case("vessel") {
+            // Report attributes that are not standard columns once per response
+            let unknown = csv::StringRecord::from(attributes.iter().map(|(key, _)| key.as_str()).filter(|key| !STANDARD_COLUMNS.contains(key) && !unknown_attributes.iter().any(|u| u == key)).collect::<Vec<&str>>());
+            print_unknown_headers(&unknown);
+            unknown_attributes.extend(unknown.iter().map(|key| key.to_string()));
+
+            // Make a record with the values in STANDARD_COLUMNS order, missing values are left empty and keep their default values
+            let mut record = csv::StringRecord::new();
+            for column in STANDARD_COLUMNS.iter() {
+                record.push_field(attributes.iter().find(|(key, _)| key == column).map(|(_, value)| value.as_str()).unwrap_or_default());
+            }
+
+            // Append to data vector, quarantining records with values that can not be read
+            match record_to_vessel_info(&record, &header_order) {
+                Ok(vessel) => data.push(vessel),
+                Err(e) => {
+                    println!("Error reading vessel from XML response, ignoring and moving on.\nVessel ignored: {}", e);
+                    quarantine_record("AISHub API", e.as_str(), attributes.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect::<Vec<String>>().join(" ").as_str());
+                }
+            }
+        }
+        // The leading status element has an ERROR attribute
+        else if let Some((_, error)) = attributes.iter().find(|(key, _)| key == "ERROR") {
+            if error == "true" {
+                let message = attributes.iter().find(|(key, _)| key == "ERROR_MESSAGE").map(|(_, value)| value.as_str()).unwrap_or("No error message");
+                return Err(io::Error::other(std::format!("AISHub API returned an error: {}", message)));
+            }
+            match attributes.iter().find(|(key, _)| key == "RECORDS") {
+                Some((_, records)) => expected_records = records.parse().ok(),
+                None => {}
+            }
+        }
+    }
+
+    // If elements were left open or there are fewer vessels than reported the response was most likely cut off
+    if depth != 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Response from AISHub API appears to be truncated, XML elements were left open"));
+    }
+    match expected_records {
+        Some(n) if n != data.len() => {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, std::format!("Response from AISHub API appears to be truncated, expected {} records but got {}", n, data.len())));
+        },
+        _ => {}
+    }
+
+    // Return the data vector
+    return Ok(data);
+}
+
+/// Saves a raw response body to {dir}/{timestamp}.{format} so it can be replayed later with --replay
+/// Creates the directory if it does not exist. Failing to save the response is not critical so errors are only printed
+fn save_raw_response(body: &str, dir: &str, output_format: &str) {
+    // Check if directory exists, if not create it
+    if !std::path::Path::new(dir).exists() {
+        match fs::create_dir_all(dir) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Error creating raw response directory {}: {}", dir, e);
+                return;
+            }
+        }
+    }
+
+    // Write response to file
+    let file_path = std::path::Path::new(dir).join(format!("{}.{}", time::UtcDateTime::now().unix_timestamp(), output_format));
+    match fs::write(&file_path, body) {
+        Ok(_) => {},
+        Err(e) => println!("Error saving raw response to {}: {}", file_path.display(), e),
+    }
+}
+
+/// Parses a saved raw response and stores the data as if it had just been collected, without making any requests
+/// The output format is taken from the file extension
+fn replay_response(file_path: &str) {
+    println!("Replaying {}", file_path);
+
+    // Get settings
+    let settings = match get_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error getting settings from settings.json file: {}", e);
+            return;
+        }
+    };
+
+    // Read the saved response
+    let body = match fs::read(file_path) {
+        Ok(b) => decode_response(&b),
+        Err(e) => {
+            println!("Error reading {}: {}", file_path, e);
+            return;
+        }
+    };
+    let output_format = match std::path::Path::new(file_path).extension().and_then(|e| e.to_str()) {
+        Some("xml") => "xml",
+        _ => "csv",
+    };
+
+    // Parse and store the data
+    let mut data = match parse_response(&body, output_format, settings.data_value_format) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("Error parsing {}: {}", file_path, e);
+            return;
+        }
+    };
+    if settings.normalize_sog_cog {
+        normalize_sog_cog(&mut data, settings.data_value_format);
+    }
+    if settings.normalize_names {
+        normalize_names(&mut data, settings.uppercase_names);
+    }
+    let data = filter_future_timestamps(data, &settings);
+    let data = filter_time_window(data, &settings);
+    let data = filter_min_sog(data, &settings);
+    let mut data = filter_polygon(data, &settings);
+    reconcile_imo_numbers(&mut data, &mut std::collections::HashMap::new(), &settings);
+    match save_data(&data, &std::collections::HashMap::new(), &settings) {
+        Ok((_, stats)) => println!("Replayed {} records from {}: {}", data.len(), file_path, stats),
+        Err(e) => println!("Error saving replayed data: {}", e),
+    }
+}
+
+/// Writes the positions of the given records to InfluxDB in one request using the line protocol
+/// Failing to write to InfluxDB is not critical since the data is already stored in the vessel files, so errors are only printed
+fn write_to_influxdb(data: &[&VesselInfo], url: &str, client: &reqwest::blocking::Client, settings: &Settings) {
+    // Make one line per record, skipping records with no position data
+    let lines: Vec<String> = data.iter().filter_map(|vessel| make_influxdb_line(vessel, settings)).collect();
+    if lines.is_empty() {
+        return;
+    }
+
+    // Make request, with the token if set
+    let mut request = client.post(url).body(lines.join("\n"));
+    match &settings.influxdb_token {
+        Some(token) => request = request.header("Authorization", format!("Token {}", token)),
+        None => {}
+    }
+
+    // Send request
+    match request.send() {
+        Ok(response) => {
+            if !response.status().is_success() {
+                println!("Warning: Writing {} records to InfluxDB failed with status {}", lines.len(), response.status());
+            }
+        },
+        Err(e) => println!("Warning: Error writing {} records to InfluxDB: {}", lines.len(), e),
+    }
+}
+
+/// Makes an InfluxDB line protocol line for a record, e.g.
+/// vessel_position,mmsi=123456789,imo=1234567,name=SHIP\ NAME lat=60.1,lon=5.2,sog=10.5,cog=123.4,heading=120 1760000000000000000
+/// SOG is in knots and COG in degrees whichever data_value_format is used. Values that are not available are left out
+/// Returns None if the record has none of the fields
+fn make_influxdb_line(vessel: &VesselInfo, settings: &Settings) -> Option<String> {
+    // Tags, empty tags are not allowed in the line protocol
+    let mut tags: Vec<String> = vec![format!("mmsi={}", vessel.mmsi)];
+    if vessel.imo != 0 {
+        tags.push(format!("imo={}", vessel.imo));
+    }
+    if !vessel.name.trim().is_empty() {
+        tags.push(format!("name={}", escape_influxdb_tag(vessel.name.trim())));
+    }
+
+    // Fields, SOG and COG are only in AIS format if they have not been normalized already
+    let scale = if settings.data_value_format == 0 && !settings.normalize_sog_cog { 10.0 } else { 1.0 };
+    let mut fields: Vec<String> = Vec::new();
+    match coordinate_to_degrees(vessel.latitude.as_str(), 90.0, settings.data_value_format) {
+        Some(lat) => fields.push(format!("lat={}", lat)),
+        None => {}
+    }
+    match coordinate_to_degrees(vessel.longitude.as_str(), 180.0, settings.data_value_format) {
+        Some(lon) => fields.push(format!("lon={}", lon)),
+        None => {}
+    }
+    match vessel.sog.map(|v| v / scale).filter(|v| *v < 102.3) {
+        Some(sog) => fields.push(format!("sog={}", sog)),
+        None => {}
+    }
+    match vessel.cog.map(|v| v / scale).filter(|v| *v < 360.0) {
+        Some(cog) => fields.push(format!("cog={}", cog)),
+        None => {}
+    }
+    if vessel.heading < 360 {
+        fields.push(format!("heading={}", vessel.heading));
+    }
+    if fields.is_empty() {
+        return None;
+    }
+
+    // Return line with the timestamp in nanoseconds
+    return Some(format!("vessel_position,{} {} {}", tags.join(","), fields.join(","), vessel.timestamp as u128 * 1_000_000_000));
+}
+
+/// Escapes commas, equal signs and spaces in an InfluxDB tag value
+fn escape_influxdb_tag(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ");
+}
+
+/// Posts a message to a Slack or Discord compatible incoming webhook
+/// The message is sent both as "text" (Slack) and "content" (Discord). Failing to notify is not critical so errors are only printed
+/// client is the client from make_http_client, reused so a notification does not make a new client with its own connections
+fn send_webhook_message(url: &str, message: &str, client: &reqwest::blocking::Client) {
+    // Make JSON payload
+    let payload = serde_json::json!({
+        "text": message,
+        "content": message,
+    });
+
+    // Send notification
+    match client.post(url).header("Content-Type", "application/json").body(payload.to_string()).send() {
+        Ok(response) => {
+            if !response.status().is_success() {
+                println!("Webhook notification failed with status {}", response.status());
+            }
+        },
+        Err(e) => println!("Error sending webhook notification: {}", e),
+    }
+}
+
+/// Makes the HTTP client used for the AISHub API requests
+/// If a proxy is set in the settings, all requests are routed through it. Both http:// and socks5:// proxies are supported
+/// Only redirects to the same host are followed, see make_redirect_policy
+fn make_http_client(settings: &Settings) -> Result<reqwest::blocking::Client, reqwest::Error> {
+    let mut builder = reqwest::blocking::Client::builder().redirect(make_redirect_policy());
+
+    // Add proxy if set
+    match &settings.proxy {
+        Some(proxy_url) => builder = builder.proxy(reqwest::Proxy::all(proxy_url)?),
+        None => {}
+    }
+
+    // Return client
+    return builder.build();
+}
+
+/// Makes the redirect policy of the HTTP client. Redirects are only followed to the same host and never from HTTPS to HTTP, at most MAX_REDIRECTS times.
+/// Other redirects fail the request, so e.g. a login or error page on another site is never parsed as data
+fn make_redirect_policy() -> reqwest::redirect::Policy {
+    return reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error(format!("more than {} redirects", MAX_REDIRECTS));
+        }
+        let previous = match attempt.previous().last() {
+            Some(p) => p,
+            None => return attempt.follow(),
+        };
+        if attempt.url().host_str() != previous.host_str() {
+            let message = format!("redirect to another host: {}", attempt.url().host_str().unwrap_or_default());
+            return attempt.error(message);
+        }
+        if previous.scheme() == "https" && attempt.url().scheme() != "https" {
+            return attempt.error("redirect from HTTPS to HTTP");
+        }
+        return attempt.follow();
+    });
+}
+
+/// Gets the order of headers in the CSV response
+/// Returns a vector with the index in the response of each of the STANDARD_COLUMNS, in the same order, or None if the column is not in the response.
+/// Based on the VesselInfo struct definition (alphabetical order) and https://www.aishub.net/api
+/// Only maps the headers, unknown headers are reported with print_unknown_headers
+fn get_header_order(headers: &csv::StringRecord) -> Vec<Option<usize>> {
+    return STANDARD_COLUMNS.iter().map(|column| headers.iter().position(|header| header == *column)).collect();
+}
+
+/// Prints the headers in a response that are not one of the STANDARD_COLUMNS, which are ignored
+fn print_unknown_headers(headers: &csv::StringRecord) {
+    for header in headers.iter().filter(|header| !STANDARD_COLUMNS.contains(header)) {
+        println!("Ignoring unknown header in CSV response: {}.\nIf this header is needed, please submit an issue to the aishub_data_collector github repository:\nhttps://github.com/G0rocks/aishub_data_collector/issues.", header);
+    }
+}
+
+/// Drops records with a timestamp more than max_future_skew_secs ahead of the clock, e.g. in the year 2099, which would otherwise block all newer records for the ship
+/// Timestamps a little ahead are accepted since the clocks of the ships and this computer are not exactly in sync
+fn filter_future_timestamps(data: Vec<VesselInfo>, settings: &Settings) -> Vec<VesselInfo> {
+    let max_timestamp = time::UtcDateTime::now().unix_timestamp() as u64 + settings.max_future_skew_secs.unwrap_or(MAX_FUTURE_SKEW_SECS_DEFAULT);
+    return data.into_iter().filter(|vessel| {
+        if vessel.timestamp > max_timestamp {
+            println!("Skipping record for MMSI {} with timestamp {}, too far in the future.", vessel.mmsi, vessel.timestamp);
+            return false;
+        }
+        return true;
+    }).collect();
+}
+
+/// Drops records with a timestamp before store_since or after store_until
+fn filter_time_window(data: Vec<VesselInfo>, settings: &Settings) -> Vec<VesselInfo> {
+    // Nothing to filter
+    if settings.store_since.is_none() && settings.store_until.is_none() {
+        return data;
+    }
+
+    return data.into_iter().filter(|vessel| {
+        let inside = settings.store_since.is_none_or(|since| vessel.timestamp >= since) && settings.store_until.is_none_or(|until| vessel.timestamp <= until);
+        if !inside {
+            println!("Skipping record for MMSI {} with timestamp {}, outside the time window to store.", vessel.mmsi, vessel.timestamp);
+        }
+        return inside;
+    }).collect();
+}
+
+/// Drops records with a speed over ground below min_sog, so only moving ships are stored
+/// Records with SOG "not available" or not readable are kept only if min_sog_keep_unavailable is set
+fn filter_min_sog(data: Vec<VesselInfo>, settings: &Settings) -> Vec<VesselInfo> {
+    // Nothing to filter
+    let min_sog = match settings.min_sog {
+        Some(m) => m,
+        None => return data,
+    };
+
+    // SOG is only in AIS format if it has not been normalized already
+    let scale = if settings.data_value_format == 0 && !settings.normalize_sog_cog { 10.0 } else { 1.0 };
+    let count = data.len();
+    let data: Vec<VesselInfo> = data.into_iter().filter(|vessel| {
+        return match vessel.sog.map(|v| v / scale).filter(|v| *v < 102.3) {
+            Some(sog) => sog >= min_sog,
+            None => settings.min_sog_keep_unavailable,
+        };
+    }).collect();
+
+    // Summarize instead of printing each record since there can be many in busy ports
+    if data.len() < count && !is_quiet() {
+        println!("Skipping {} records with SOG below min_sog of {} knots or not available.", count - data.len(), min_sog);
+    }
+    return data;
+}
+
+/// Drops records with a position outside the polygon, records without a readable position are dropped too
+fn filter_polygon(data: Vec<VesselInfo>, settings: &Settings) -> Vec<VesselInfo> {
+    // Nothing to filter
+    let vertices = match &settings.polygon {
+        Some(v) => v,
+        None => return data,
+    };
+
+    let count = data.len();
+    let data: Vec<VesselInfo> = data.into_iter().filter(|vessel| {
+        let lat = coordinate_to_degrees(vessel.latitude.as_str(), 90.0, settings.data_value_format);
+        let lon = coordinate_to_degrees(vessel.longitude.as_str(), 180.0, settings.data_value_format);
+        return match (lat, lon) {
+            (Some(lat), Some(lon)) => is_inside_polygon(lat, lon, vertices),
+            _ => false,
+        };
+    }).collect();
+
+    // Summarize instead of printing each record since there can be many
+    if data.len() < count && !is_quiet() {
+        println!("Skipping {} records outside the polygon.", count - data.len());
+    }
+    return data;
+}
+
+/// Checks if a position is inside a polygon given as (latitude, longitude) vertices with the ray casting method,
+/// i.e. counts how many edges a line from the position going east crosses. An odd number of crossings means the position is inside
+fn is_inside_polygon(lat: f64, lon: f64, vertices: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    let mut previous = match vertices.last() {
+        Some(v) => *v,
+        None => return false,
+    };
+    for vertex in vertices {
+        let (lat1, lon1) = previous;
+        let (lat2, lon2) = *vertex;
+        // The edge crosses the latitude of the position east of it
+        if (lat1 > lat) != (lat2 > lat) && lon < lon1 + (lat - lat1) / (lat2 - lat1) * (lon2 - lon1) {
+            inside = !inside;
+        }
+        previous = *vertex;
+    }
+    return inside;
+}
+
+/// Saves the data with save_data and handles write errors, e.g. a full disk, according to on_write_error:
+/// "skip" returns the error right away, "retry" tries again up to write_retries times WRITE_RETRY_DELAY seconds apart
+/// and "pause" keeps trying again with a growing delay, up to WRITE_PAUSE_MAX_DELAY seconds, until the data is saved or ctrl+C is pressed.
+/// Only errors that can clear up are tried again, see is_retryable_write_error, other errors are returned right away whatever on_write_error is.
+/// Records written before an error are skipped as duplicates when trying again, so they are not in the returned records and are counted as duplicates
+fn save_data_with_policy<'a>(data: &'a [VesselInfo], labels: &std::collections::HashMap<String, String>, settings: &Settings) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+    let mut attempt: u32 = 0;
+    let mut delay = WRITE_RETRY_DELAY;
+    loop {
+        let error = match save_data(data, labels, settings) {
+            Ok(saved) => return Ok(saved),
+            Err(e) => e,
+        };
+        attempt += 1;
+
+        // Only errors from the disk or database that can clear up are tried again, e.g. a full disk. Others, e.g. a file with other columns, would fail the same way forever
+        if !is_retryable_write_error(error.as_ref()) {
+            return Err(error);
+        }
+
+        // Decide whether to try again
+        match settings.on_write_error.as_str() {
+            "retry" if attempt <= settings.write_retries.unwrap_or(WRITE_RETRIES_DEFAULT) => {
+                println!("Error saving data: {}\nTrying again in {} seconds (retry {} of {}).", error, delay, attempt, settings.write_retries.unwrap_or(WRITE_RETRIES_DEFAULT));
+            },
+            "pause" => {
+                println!("Error saving data: {}\nPaused, trying again in {} seconds so no data is lost. Free up disk space or fix the error to continue.", error, delay);
+            },
+            _ => return Err(error),
+        }
+
+        // Wait before trying again, giving up if ctrl+C is pressed
+        if !sleep_unless_shutdown(std::time::Duration::from_secs(delay)) {
+            return Err(error);
+        }
+        if settings.on_write_error == "pause" {
+            delay = (delay * 2).min(WRITE_PAUSE_MAX_DELAY);
+        }
+    }
+}
+
+/// Checks if an error from saving data can clear up by itself or once the user frees up disk space or fixes permissions, so it is worth trying again
+/// e.g. a full disk, missing permissions or a locked database
+fn is_retryable_write_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    match find_io_error_kind(error) {
+        Some(kind) => return matches!(kind, io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::ResourceBusy | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock),
+        None => return false,
+    }
+}
+
+/// Finds the I/O error kind of an error, looking through the errors it wraps and the I/O error in a CSV error.
+/// SQLite errors are mapped to the matching I/O error kind. Returns None if the error is not caused by an I/O error
+fn find_io_error_kind(error: &(dyn std::error::Error + 'static)) -> Option<io::ErrorKind> {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        match e.downcast_ref::<io::Error>() {
+            Some(io_error) => return Some(io_error.kind()),
+            None => {}
+        }
+        match e.downcast_ref::<csv::Error>().map(|csv_error| csv_error.kind()) {
+            Some(csv::ErrorKind::Io(io_error)) => return Some(io_error.kind()),
+            _ => {}
+        }
+        match e.downcast_ref::<rusqlite::ffi::Error>() {
+            Some(sqlite_error) => {
+                return match sqlite_error.code {
+                    rusqlite::ffi::ErrorCode::DiskFull => Some(io::ErrorKind::StorageFull),
+                    rusqlite::ffi::ErrorCode::DatabaseBusy | rusqlite::ffi::ErrorCode::DatabaseLocked => Some(io::ErrorKind::ResourceBusy),
+                    rusqlite::ffi::ErrorCode::ReadOnly => Some(io::ErrorKind::ReadOnlyFilesystem),
+                    rusqlite::ffi::ErrorCode::PermissionDenied => Some(io::ErrorKind::PermissionDenied),
+                    _ => None,
+                };
+            },
+            None => {}
+        }
+        current = e.source();
+    }
+    return None;
+}
+
+/// Adds context to an error message, e.g. which file could not be written, keeping the I/O error kind so is_retryable_write_error still works
+fn add_error_context(context: &str, error: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+    match find_io_error_kind(error.as_ref()) {
+        Some(kind) => return Box::new(io::Error::new(kind, format!("{}: {}", context, error))),
+        None => return Box::from(format!("{}: {}", context, error)),
+    }
+}
+
+/// Remembers the IMO number of each MMSI number in mmsi_to_imo and fills in the IMO number of records
+/// that are missing it but have an MMSI number known to belong to an IMO number, so they are saved to the imo file unless id_priority is "mmsi".
+/// The filled in IMO number is written to the IMO column even though AISHub did not send it
+fn reconcile_imo_numbers(data: &mut [VesselInfo], mmsi_to_imo: &mut std::collections::HashMap<u64, u64>, settings: &Settings) {
+    // Remember IMO numbers first so records in the same response can be reconciled
+    for vessel in data.iter() {
+        if vessel.imo != 0 && vessel.mmsi != 0 {
+            mmsi_to_imo.insert(vessel.mmsi, vessel.imo);
+        }
+    }
+
+    // Fill in missing IMO numbers
+    for vessel in data.iter_mut() {
+        if vessel.imo == 0 && vessel.mmsi != 0 {
+            match mmsi_to_imo.get(&vessel.mmsi) {
+                Some(imo) => {
+                    if settings.prefers_mmsi() {
+                        println!("Record for MMSI {} has no IMO number, filling in IMO {} seen with that MMSI earlier.", vessel.mmsi, imo);
+                    } else {
+                        println!("Record for MMSI {} has no IMO number, filling in IMO {} seen with that MMSI earlier and saving it to the file of that IMO.", vessel.mmsi, imo);
+                    }
+                    vessel.imo = *imo;
+                },
+                None => {}
+            }
+        }
+    }
+}
+
+/// Function that saves the data to the database, i.e. to every sink in the sinks setting, see Sink
+/// If the files don't exist, creates them
+/// If the files already exist, appends to them
+/// Note: Prioritizes IMO number over MMSI number, so if both exist, saves to IMO file only. The other way around if id_priority is "mmsi"
+/// Records without either number are dropped, or saved to UNIDENTIFIED_FILE if keep_unidentified is set, and are not in the returned records
+/// Returns the records that were written to the first sink, i.e. without the ones skipped as duplicates, and the number of records written and skipped
+/// labels are the labels from ships.csv, written to the LABEL column if label_column is set
+fn save_data<'a>(data: &'a [VesselInfo], labels: &std::collections::HashMap<String, String>, settings: &Settings) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+    let mut written: Vec<&VesselInfo> = Vec::new();
+    let mut stats = SaveStats::default();
+
+    // Nothing to save
+    if data.is_empty() {
+        return Ok((written, stats));
+    }
+
+    // Check if data folder exists, if not, create it
+    if !std::path::Path::new("data").exists() {
+        fs::create_dir("data")?;
+    }
+
+    // Records without an IMO or MMSI number can not be saved to a vessel file, count them and save them to the unidentified file if set.
+    // An error saving them does not stop the sinks, it is returned once all sinks have been tried like a sink error
+    let mut save_error: Option<Box<dyn std::error::Error>> = None;
+    let unidentified: Vec<&VesselInfo> = data.iter().filter(|v| v.imo == 0 && v.mmsi == 0).collect();
+    if !unidentified.is_empty() {
+        if settings.keep_unidentified {
+            match save_unidentified_records(&unidentified, &mut stats, settings) {
+                Ok(()) => {
+                    if !is_quiet() && stats.unidentified > 0 {
+                        println!("{} records without an IMO or MMSI number saved to {}.", stats.unidentified, UNIDENTIFIED_FILE);
+                    }
+                },
+                Err(e) => save_error = Some(add_error_context(format!("Error saving records without an IMO or MMSI number to {}", UNIDENTIFIED_FILE).as_str(), e)),
+            }
+        } else {
+            stats.skipped += unidentified.len();
+            if !is_quiet() {
+                println!("{} records without an IMO or MMSI number dropped. Set keep_unidentified to save them to {}.", unidentified.len(), UNIDENTIFIED_FILE);
+            }
+        }
+    }
+
+    // Write the records to every sink, a failing sink does not stop the others and the first error is returned once all sinks have been tried.
+    // The written records and counts of the first sink are returned
+    for (index, sink) in make_sinks(labels, settings)?.iter_mut().enumerate() {
+        match sink.write_batch(data) {
+            Ok((sink_written, sink_stats)) => {
+                if index == 0 {
+                    written = sink_written;
+                    stats = SaveStats { deduped: stats.deduped + sink_stats.deduped, skipped: stats.skipped + sink_stats.skipped, unidentified: stats.unidentified, ..sink_stats };
+                } else if is_verbose() {
+                    println!("Saved data to the {} sink: {}.", sink.name(), sink_stats);
+                }
+            },
+            Err(e) => {
+                match save_error {
+                    Some(_) => println!("Error saving data to the {} sink: {}", sink.name(), e),
+                    None => save_error = Some(add_error_context(format!("Error saving data to the {} sink", sink.name()).as_str(), e)),
+                }
+            }
+        }
+    }
+    match save_error {
+        Some(e) => return Err(e),
+        None => {}
+    }
+
+    // Return written records
+    return Ok((written, stats));
+}
+
+/// Appends records without an IMO or MMSI number to UNIDENTIFIED_FILE, skipping the ones already in it with the same timestamp and position,
+/// since AISHub sends the last record of a vessel again every cycle until it sends a new one.
+/// Adds the records written to stats.unidentified and the ones skipped to stats.deduped
+fn save_unidentified_records(unidentified: &[&VesselInfo], stats: &mut SaveStats, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let file_path = std::path::Path::new(UNIDENTIFIED_FILE);
+
+    // Get the timestamp and position of the records already in the file, as written
+    let mut saved: std::collections::HashSet<(String, String, String)> = std::collections::HashSet::new();
+    if file_path.exists() {
+        repair_incomplete_last_line(file_path, settings)?;
+        let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_path(file_path)?;
+        let headers: Vec<String> = reader.headers()?.iter().map(|h| settings.standard_header(h)).collect();
+        let position = |column: &str| headers.iter().position(|h| h == column);
+        match (position("TSTAMP"), position("LATITUDE"), position("LONGITUDE")) {
+            (Some(timestamp), Some(latitude), Some(longitude)) => {
+                for result in reader.records() {
+                    let record = result?;
+                    saved.insert((record[timestamp].to_string(), record[latitude].to_string(), record[longitude].to_string()));
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // Skip records already in the file or earlier in this cycle
+    let mut rows: Vec<(&VesselInfo, DerivedColumns)> = Vec::new();
+    for vessel in unidentified {
+        let derived = make_derived_columns(vessel, &LastRecord { headers: csv::StringRecord::new(), record: None, count: 0 }, settings);
+        let key = (
+            get_column_value("TSTAMP", vessel, &derived, settings).unwrap_or_default(),
+            get_column_value("LATITUDE", vessel, &derived, settings).unwrap_or_default(),
+            get_column_value("LONGITUDE", vessel, &derived, settings).unwrap_or_default(),
+        );
+        if saved.insert(key) {
+            rows.push((*vessel, derived));
+        } else {
+            stats.deduped += 1;
+        }
+    }
+
+    // Append the new records
+    if !rows.is_empty() {
+        append_to_combined_file(file_path, &rows, settings)?;
+    }
+    stats.unidentified += rows.len();
+
+    // Return Ok
+    return Ok(());
+}
+
+/// A place the collected records are stored, one of the sinks setting. Each cycle's records are written to every sink
+trait Sink {
+    /// Gets the name of the sink in the sinks setting, e.g. "csv"
+    fn name(&self) -> &str;
+
+    /// Writes the records of a cycle, skipping the ones the sink already has
+    /// Returns the records that were written and the number of records written and skipped
+    fn write_batch<'a>(&mut self, data: &'a [VesselInfo]) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>>;
+}
+
+/// Sink saving the records to a CSV file per vessel, see save_data_to_csv
+struct CsvSink<'s> {
+    /// Labels from ships.csv, written to the LABEL column if label_column is set
+    labels: &'s std::collections::HashMap<String, String>,
+    settings: &'s Settings,
+}
+
+impl Sink for CsvSink<'_> {
+    fn name(&self) -> &str {
+        return "csv";
+    }
+
+    fn write_batch<'a>(&mut self, data: &'a [VesselInfo]) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+        let mut stats = SaveStats::default();
+        let written = save_data_to_csv(data, self.labels, &mut stats, self.settings)?;
+        stats.written = written.len();
+        return Ok((written, stats));
+    }
+}
+
+/// Sink saving the records to a Parquet file per day, see save_data_to_parquet
+struct ParquetSink<'s> {
+    settings: &'s Settings,
+}
+
+impl Sink for ParquetSink<'_> {
+    fn name(&self) -> &str {
+        return "parquet";
+    }
+
+    fn write_batch<'a>(&mut self, data: &'a [VesselInfo]) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+        let mut stats = SaveStats::default();
+        let written = save_data_to_parquet(data, &mut stats, self.settings)?;
+        stats.written = written.len();
+        return Ok((written, stats));
+    }
+}
+
+/// Sink saving the records to the positions table of a SQLite database, sqlite_path or SQLITE_FILE_DEFAULT
+struct SqliteSink {
+    connection: rusqlite::Connection,
+}
+
+impl SqliteSink {
+    /// Opens the database, creating it, its folder and the positions table if they do not exist.
+    /// The table has the standard columns with the types of the Parquet files and a row per record, unique by IMO, MMSI and TSTAMP
+    fn open(file_path: &std::path::Path) -> Result<SqliteSink, Box<dyn std::error::Error>> {
+        match file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent)?,
+            _ => {}
+        }
+        let connection = rusqlite::Connection::open(file_path)?;
+
+        // Make the table with a column for each standard column
+        let columns: Vec<String> = make_parquet_schema().fields().iter().map(|field| {
+            let column_type = match field.data_type() {
+                arrow_schema::DataType::Utf8 => "TEXT",
+                arrow_schema::DataType::Float64 => "REAL",
+                _ => "INTEGER",
+            };
+            return format!("\"{}\" {}", field.name(), column_type);
+        }).collect();
+        connection.execute(&format!("CREATE TABLE IF NOT EXISTS positions ({}, UNIQUE (\"IMO\", \"MMSI\", \"TSTAMP\"))", columns.join(", ")), ())?;
+
+        // Return sink
+        return Ok(SqliteSink { connection });
+    }
+}
+
+impl Sink for SqliteSink {
+    fn name(&self) -> &str {
+        return "sqlite";
+    }
+
+    /// Inserts the records in one transaction. Records without an IMO or MMSI number are not saved, see save_data,
+    /// and records with the same IMO number, MMSI number and timestamp as a row in the table are skipped as duplicates
+    fn write_batch<'a>(&mut self, data: &'a [VesselInfo]) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+        let mut written: Vec<&VesselInfo> = Vec::new();
+        let mut stats = SaveStats::default();
+
+        // Sort by timestamp so the rows are in the same order as they were received
+        let mut sorted_data: Vec<&VesselInfo> = data.iter().filter(|v| v.imo != 0 || v.mmsi != 0).collect();
+        sorted_data.sort_by_key(|v| v.timestamp);
+
+        // Insert the records, all or none are saved
+        let transaction = self.connection.transaction()?;
+        {
+            let columns: Vec<String> = STANDARD_COLUMNS.iter().map(|column| format!("\"{}\"", column)).collect();
+            let mut statement = transaction.prepare(&format!("INSERT OR IGNORE INTO positions ({}) VALUES ({})", columns.join(", "), vec!["?"; columns.len()].join(", ")))?;
+            for vessel in sorted_data {
+                if statement.execute(rusqlite::params_from_iter(make_sqlite_values(vessel)))? == 0 {
+                    stats.deduped += 1;
+                    continue;
+                }
+                written.push(vessel);
+            }
+        }
+        transaction.commit()?;
+        stats.written = written.len();
+
+        // Return written records
+        return Ok((written, stats));
+    }
+}
+
+/// Makes the sinks in the sinks setting, or the one in storage_format if it is not set, see Settings::get_sinks
+fn make_sinks<'s>(labels: &'s std::collections::HashMap<String, String>, settings: &'s Settings) -> Result<Vec<Box<dyn Sink + 's>>, Box<dyn std::error::Error>> {
+    let mut sinks: Vec<Box<dyn Sink + 's>> = Vec::new();
+    for name in settings.get_sinks() {
+        match name.as_str() {
+            "csv" => sinks.push(Box::new(CsvSink { labels, settings })),
+            "parquet" => sinks.push(Box::new(ParquetSink { settings })),
+            "sqlite" => sinks.push(Box::new(SqliteSink::open(std::path::Path::new(settings.sqlite_path.as_deref().unwrap_or(SQLITE_FILE_DEFAULT)))?)),
+            other => return Err(Box::from(format!("Unknown sink {}", other))),
+        }
+    }
+
+    // Return sinks
+    return Ok(sinks);
+}
+
+/// Makes the values of a record for the positions table of the SQLite sink, in the order of STANDARD_COLUMNS
+/// Unknown positions, SOG and COG are NULL, like in the Parquet files
+fn make_sqlite_values(vessel: &VesselInfo) -> Vec<rusqlite::types::Value> {
+    let integer = |value: u64| rusqlite::types::Value::Integer(value as i64);
+    let text = |value: &str| rusqlite::types::Value::Text(value.to_string());
+    let real = |value: Option<f64>| value.map(rusqlite::types::Value::Real).unwrap_or(rusqlite::types::Value::Null);
+    return STANDARD_COLUMNS.iter().map(|column| {
+        return match *column {
+            "A" => integer(vessel.a),
+            "B" => integer(vessel.b),
+            "C" => integer(vessel.c),
+            "CALLSIGN" => text(vessel.callsign.as_str()),
+            "COG" => real(vessel.cog),
+            "D" => integer(vessel.d),
+            "DEST" => text(vessel.dest.as_str()),
+            "DRAUGHT" => integer(vessel.draught),
+            "DEVICE" => text(vessel.device.as_str()),
+            "ETA" => integer(vessel.eta),
+            "HEADING" => integer(vessel.heading),
+            "IMO" => integer(vessel.imo),
+            "LATITUDE" => real(vessel.latitude.trim().parse().ok()),
+            "LONGITUDE" => real(vessel.longitude.trim().parse().ok()),
+            "MMSI" => integer(vessel.mmsi),
+            "NAME" => text(vessel.name.as_str()),
+            "NAVSTAT" => text(vessel.navstat.as_str()),
+            "PAC" => integer(vessel.pac as u64),
+            "ROT" => text(vessel.rot.as_str()),
+            "SOG" => real(vessel.sog),
+            "TSTAMP" => integer(vessel.timestamp),
+            _ => integer(vessel.vessel_type),
+        };
+    }).collect();
+}
+
+/// Saves the data to a CSV file per vessel in data/imo and data/mmsi, and to the combined file if append_combined is set
+/// Records without an IMO or MMSI number are skipped, they are handled by save_data
+/// Returns the records that were written and adds the skipped records to stats
+fn save_data_to_csv<'a>(data: &'a [VesselInfo], labels: &std::collections::HashMap<String, String>, stats: &mut SaveStats, settings: &Settings) -> Result<Vec<&'a VesselInfo>, Box<dyn std::error::Error>> {
+    // Check if imo folder exists, if not create it
+    if !std::path::Path::new("data/imo").exists() {
+        fs::create_dir("data/imo")?;
+    }
+
+    // Check if mmsi folder exists, if not create it
+    if !std::path::Path::new("data/mmsi").exists() {
+        fs::create_dir("data/mmsi")?;
+    }
+
+    // Sort by identifier and timestamp so the output order is the same every run and each vessel's records are appended in timestamp order
+    let mut sorted_data: Vec<&VesselInfo> = data.iter().collect();
+    if settings.prefers_mmsi() {
+        sorted_data.sort_by_key(|v| (v.mmsi == 0, v.mmsi, v.imo, v.timestamp));
+    } else {
+        sorted_data.sort_by_key(|v| (v.imo == 0, v.imo, v.mmsi, v.timestamp));
+    }
+
+    // Group the records by the file they are saved to, so each file is opened once per cycle however many of its records there are
+    let mut files: Vec<(std::path::PathBuf, Vec<&VesselInfo>)> = Vec::new();
+    let mut file_indexes: std::collections::HashMap<std::path::PathBuf, usize> = std::collections::HashMap::new();
+    for vessel in sorted_data {
+        // if MMSI number exists and is prioritized, save to mmsi folder
+        let (folder, id) = if vessel.mmsi != 0 && settings.prefers_mmsi() {
+            ("data/mmsi", vessel.mmsi)
+        }
+        // if IMO number exists, save to imo folder
+        else if vessel.imo != 0 {
+            ("data/imo", vessel.imo)
+        }
+        // if MMSI number exists, save to mmsi folder
+        else if vessel.mmsi != 0 {
+            ("data/mmsi", vessel.mmsi)
+        } else {
+            continue;
+        };
+        let file_path = make_vessel_file_path(vessel, folder, id, settings)?;
+        match file_indexes.get(&file_path) {
+            Some(index) => files[*index].1.push(vessel),
+            None => {
+                file_indexes.insert(file_path.clone(), files.len());
+                files.push((file_path, vec![vessel]));
+            }
+        }
+    }
+
+    // Save the records of each file, stopping at the first error
+    let mut rows: Vec<(&VesselInfo, DerivedColumns)> = Vec::new();
+    let mut save_error: Option<Box<dyn std::error::Error>> = None;
+    for (file_path, records) in &files {
+        match save_vessel_records(file_path, records, labels, stats, settings) {
+            Ok(mut file_rows) => rows.append(&mut file_rows),
+            Err(e) => {
+                save_error = Some(e);
+                break;
+            }
+        }
+    }
+
+    // Also append the written records to the combined file if set, including the ones written before an error
+    match &settings.append_combined {
+        Some(combined_path) if !rows.is_empty() => {
+            match append_to_combined_file(std::path::Path::new(combined_path), &rows, settings) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(add_error_context(format!("Error writing data to combined CSV file {}", combined_path).as_str(), e));
+                }
+            }
+        },
+        _ => {}
+    }
+    match save_error {
+        Some(e) => return Err(e),
+        None => {}
+    }
+    // Return written records
+    return Ok(rows.into_iter().map(|(vessel, _)| vessel).collect());
+}
+
+/// Saves the records of a vessel file, given in timestamp order, to the file
+/// Skips records whose timestamp is not newer than the latest timestamp in the file or the record before them
+/// If max_file_mb is set and the file has grown bigger than that, the file is rolled over before appending
+/// Returns the records that were written, along with the values of their optional columns, and adds the skipped records to stats
+fn save_vessel_records<'a>(file_path: &std::path::Path, records: &[&'a VesselInfo], labels: &std::collections::HashMap<String, String>, stats: &mut SaveStats, settings: &Settings) -> Result<Vec<(&'a VesselInfo, DerivedColumns)>, Box<dyn std::error::Error>> {
+    // Remove an incomplete last line left by an interrupted write so the file can be read again, and convert files written by older versions with commas between the values
+    if file_path.exists() {
+        repair_incomplete_last_line(file_path, settings)?;
+        convert_legacy_delimiter(file_path, settings)?;
+    }
+
+    // Check if file exists, if not create it with headers
+    if !file_path.exists() {
+        make_empty_csv_file(file_path, settings)?;
+    }
+
+    // Get the last record in the file, each record is compared with the one before it to avoid duplicates
+    let mut last_record = get_last_record(file_path, settings)?;
+    let file_headers = last_record.headers.clone();
+    let file_record_count = last_record.count;
+    let mut rows: Vec<(&VesselInfo, DerivedColumns)> = Vec::new();
+    for vessel in records {
+        if vessel.timestamp <= last_record.timestamp()? {
+            stats.deduped += 1;
+            continue;
+        }
+
+        // Skip records that are most likely the same position resent with a slightly different timestamp
+        if is_near_duplicate(vessel, &last_record, settings)? {
+            stats.deduped += 1;
+            if is_verbose() {
+                println!("Skipping record of {} at {}: at the same position as the last record, within dedup_window_secs of it.", file_path.display(), vessel.timestamp);
+            }
+            continue;
+        }
+
+        // Get values of the optional columns that depend on the last record, and the label by IMO number or by MMSI number, depending on which the ship is listed by in ships.csv
+        let mut derived = make_derived_columns(vessel, &last_record, settings);
+        derived.label = labels.get(&format!("IMO {}", vessel.imo)).or(labels.get(&format!("MMSI {}", vessel.mmsi))).cloned();
+
+        // Skip suspect records unless they are flagged
+        if derived.suspect == Some(true) && !settings.flags_suspect_positions() {
+            println!("Skipping record of {} at {}: the implied speed from the previous record is above {} knots.", file_path.display(), vessel.timestamp, settings.max_implied_speed_knots.unwrap_or_default());
+            stats.skipped += 1;
+            continue;
+        }
+
+        // The record is the last record the next one is compared with
+        last_record = LastRecord {
+            headers: make_headers(settings).into_iter().collect(),
+            record: Some(make_record_values(vessel, &derived, settings)?.into_iter().collect()),
+            count: last_record.count + 1,
+        };
+        rows.push((*vessel, derived));
+    }
+
+    // Nothing to write
+    if rows.is_empty() {
+        return Ok(rows);
+    }
+
+    // Check the file has the same columns as the rows that are about to be written, e.g. an optional column may have been turned on since the file was made
+    check_headers(&file_headers, file_path, settings)?;
+
+    // Remove the oldest records so the file has at most max_records_per_ship records after these are added.
+    // If there are more new records than that, only the newest ones are written and the others are counted as skipped
+    match settings.max_records_per_ship {
+        Some(max_records) => {
+            let max_records = max_records.max(1);
+            if file_record_count + rows.len() > max_records {
+                remove_oldest_records(file_path, max_records.saturating_sub(rows.len()), settings)?;
+            }
+            let first_row = rows.len().saturating_sub(max_records);
+            stats.skipped += first_row;
+            rows = rows.split_off(first_row);
+        },
+        None => {}
+    }
+
+    // Roll the file over if it has grown too big
+    match settings.max_file_mb {
+        Some(max_mb) => {
+            if fs::metadata(file_path)?.len() > max_mb * 1024 * 1024 {
+                roll_over_file(file_path, settings)?;
+            }
+        },
+        None => {}
+    }
+
+    // Append data to file
+    match append_to_vessel_file(file_path, &rows, settings) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(add_error_context("Error writing data to CSV file", e));
+        }
+    };
+    if settings.fsync_after_write {
+        sync_file(file_path)?;
+    }
+
+    // Keep the metadata file up to date if set
+    if settings.file_metadata {
+        update_file_metadata(file_path, settings)?;
+    }
+
+    // Return written records
+    return Ok(rows);
+}
+
+/// Appends records to a vessel file, opening it once. Gzip compressed files can not be appended to, so they are written again with the new records at the end
+fn append_to_vessel_file(file_path: &std::path::Path, rows: &[(&VesselInfo, DerivedColumns)], settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    if is_gzip_file(file_path) {
+        let mut contents: Vec<u8> = Vec::new();
+        open_file_reader(file_path)?.read_to_end(&mut contents)?;
+        let mut wtr = make_csv_writer_builder(settings).from_writer(contents);
+        write_records_to_file(&mut wtr, rows, settings)?;
+        replace_file_contents(file_path, wtr.get_ref())?;
+    } else {
+        let mut wtr = make_csv_writer_builder(settings)
+            .from_writer(fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file_path)?);
+        write_records_to_file(&mut wtr, rows, settings)?;
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Syncs a file to disk with fsync, see fsync_after_write, and then its folder so a new or replaced file is also still there after a power loss.
+/// Folders can not be opened on Windows, where the file's folder entry does not need to be synced, so errors syncing the folder are ignored
+fn sync_file(file_path: &std::path::Path) -> Result<(), io::Error> {
+    fs::File::open(file_path)?.sync_all()?;
+    match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            match fs::File::open(parent).and_then(|folder| folder.sync_all()) {
+                Ok(_) => {},
+                Err(_) => {}
+            }
+        },
+        _ => {}
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Checks if a vessel file is gzip compressed, i.e. ends with .gz, see compress_output
+fn is_gzip_file(file_path: &std::path::Path) -> bool {
+    return file_path.extension().and_then(|e| e.to_str()) == Some("gz");
+}
+
+/// Opens a file for reading, decompressing it on the fly if it is gzip compressed
+fn open_file_reader(file_path: &std::path::Path) -> Result<Box<dyn Read>, io::Error> {
+    let file = io::BufReader::new(fs::File::open(file_path)?);
+    if is_gzip_file(file_path) {
+        return Ok(Box::new(flate2::bufread::MultiGzDecoder::new(file)));
+    }
+    return Ok(Box::new(file));
+}
+
+/// Replaces the contents of a file, gzip compressing them if the file is compressed.
+/// The contents are written to a temporary file first and moved in place so the file is never left half written
+fn replace_file_contents(file_path: &std::path::Path, contents: &[u8]) -> Result<(), io::Error> {
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path"))?;
+    let tmp_path = file_path.with_file_name(format!("{}.tmp", file_name));
+    if is_gzip_file(file_path) {
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&tmp_path)?, flate2::Compression::default());
+        encoder.write_all(contents)?;
+        encoder.finish()?;
+    } else {
+        fs::write(&tmp_path, contents)?;
+    }
+    fs::rename(&tmp_path, file_path)?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Makes the path of the file a vessel's record is saved to, e.g. data/imo/NAME_123.csv
+/// If partition_by_date is set it is the file for the day of the record's timestamp instead, e.g. data/imo/123/2025-01-31.csv, and the vessel's folder is created if needed.
+/// A record saved from one day's file never has to be compared with another day's file, since records with the same timestamp always have the same day.
+/// Records without a timestamp are saved to today's file
+/// If path_template is set the path is made from it instead, see make_templated_path
+fn make_vessel_file_path(vessel: &VesselInfo, folder: &str, id: u64, settings: &Settings) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    // Use the path template instead if set
+    match &settings.path_template {
+        Some(template) => {
+            let id_type = std::path::Path::new(folder).file_name().and_then(|f| f.to_str()).unwrap_or_default();
+            let file_path = make_templated_path(template, vessel, id_type, id, settings)?;
+            match file_path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => fs::create_dir_all(parent)?,
+                _ => {}
+            }
+            return Ok(file_path);
+        },
+        None => {}
+    }
+
+    // One growing file per vessel
+    if !settings.partition_by_date {
+        let file_path = std::path::Path::new(folder).join(make_vessel_filename(vessel.name.as_str(), id, settings));
+
+        // Keep appending to a file whose name only differs by case, e.g. made before filename_case was set
+        if !file_path.exists() {
+            match find_file_differing_by_case(&file_path)? {
+                Some(existing) => return Ok(existing),
+                None => {}
+            }
+        }
+
+        // Keep appending to a file whose name only differs by spaces or case, e.g. made before normalize_names was set
+        if settings.normalize_names && !file_path.exists() {
+            match find_file_with_normalized_name(std::path::Path::new(folder), &file_path, id, settings)? {
+                Some(existing) => return Ok(existing),
+                None => {}
+            }
+        }
+        return Ok(file_path);
+    }
+
+    // One file per day in the vessel's folder
+    let vessel_folder = std::path::Path::new(folder).join(id.to_string());
+    if !vessel_folder.exists() {
+        fs::create_dir(&vessel_folder)?;
+    }
+    let datetime = if vessel.timestamp > 0 {
+        time::UtcDateTime::from_unix_timestamp(vessel.timestamp as i64)?
+    } else {
+        time::UtcDateTime::now()
+    };
+    let extension = if settings.compress_output { "csv.gz" } else { "csv" };
+    return Ok(vessel_folder.join(format!("{}.{}", format_date(datetime), extension)));
+}
+
+/// Makes the path of a vessel file from path_template by replacing the placeholders:
+/// {id_type} "imo" or "mmsi", {id} the IMO or MMSI number, {name} the vessel name with characters that are not valid in filenames replaced with an underscore,
+/// or empty if it has no name, {date} the day of the record's timestamp, e.g. 2025-01-31, or today if it has no timestamp, and {type_category} the kind of ship
+/// from its type, see get_type_category. ".gz" is added if compress_output is set
+fn make_templated_path(template: &str, vessel: &VesselInfo, id_type: &str, id: u64, settings: &Settings) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    // Make the name safe to use in a path, also as a folder name
+    let mut name = apply_filename_case(vessel.name.trim(), settings);
+    for invalid_char in INVALID_FILENAME_CHARACTERS.iter() {
+        name = name.replace(*invalid_char, "_");
+    }
+    if name.chars().all(|c| c == '.') {
+        name = name.replace('.', "_");
+    }
+
+    let datetime = if vessel.timestamp > 0 {
+        time::UtcDateTime::from_unix_timestamp(vessel.timestamp as i64)?
+    } else {
+        time::UtcDateTime::now()
+    };
+    let mut path = template
+        .replace("{id_type}", id_type)
+        .replace("{id}", id.to_string().as_str())
+        .replace("{name}", name.as_str())
+        .replace("{date}", format_date(datetime).as_str())
+        .replace("{type_category}", get_type_category(vessel.vessel_type));
+    if settings.compress_output {
+        path.push_str(".gz");
+    }
+
+    // Return path
+    return Ok(std::path::PathBuf::from(path));
+}
+
+/// Gets the kind of ship from its AIS ship type, e.g. 70 -> "cargo", used for {type_category} in path_template
+/// Returns "unknown" if the type is 0 (not available) or not a known type
+fn get_type_category(vessel_type: u64) -> &'static str {
+    match vessel_type {
+        20..=29 => return "wing_in_ground",
+        30 => return "fishing",
+        31 | 32 => return "towing",
+        33 => return "dredging",
+        34 => return "diving",
+        35 => return "military",
+        36 => return "sailing",
+        37 => return "pleasure_craft",
+        40..=49 => return "high_speed_craft",
+        50 => return "pilot",
+        51 => return "search_and_rescue",
+        52 => return "tug",
+        53 => return "port_tender",
+        55 => return "law_enforcement",
+        54 | 56..=59 => return "special_craft",
+        60..=69 => return "passenger",
+        70..=79 => return "cargo",
+        80..=89 => return "tanker",
+        90..=99 => return "other",
+        _ => return "unknown",
+    }
+}
+
+/// Finds a vessel file in a folder with the given identifier whose vessel name is the same as in file_path once normalized, see normalize_names
+/// Rolled over files are not matched. Returns None if there is no such file
+fn find_file_with_normalized_name(folder: &std::path::Path, file_path: &std::path::Path, id: u64, settings: &Settings) -> Result<Option<std::path::PathBuf>, io::Error> {
+    if !folder.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if !path.is_file() || get_id_from_filename(&path) != Some(id) {
+            continue;
+        }
+        let name = match get_vessel_file_stem(&path).and_then(|s| s.rsplit_once('_')) {
+            Some((name, _)) => name,
+            None => "",
+        };
+        if std::path::Path::new(folder).join(make_vessel_filename(normalize_name(name, settings.uppercase_names).as_str(), id, settings)) == file_path {
+            return Ok(Some(path));
+        }
+    }
+
+    // No matching file
+    return Ok(None);
+}
+
+/// Finds a file in the folder of file_path whose name only differs from it by case, e.g. Name_123.csv for NAME_123.csv
+/// Returns None if there is no such file or the folder does not exist
+fn find_file_differing_by_case(file_path: &std::path::Path) -> Result<Option<std::path::PathBuf>, io::Error> {
+    let (folder, file_name) = match (file_path.parent(), file_path.file_name().and_then(|n| n.to_str())) {
+        (Some(folder), Some(file_name)) => (folder, file_name.to_lowercase()),
+        _ => return Ok(None),
+    };
+    if !folder.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_file() && path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.to_lowercase() == file_name) {
+            return Ok(Some(path));
+        }
+    }
+
+    // No matching file
+    return Ok(None);
+}
+
+/// Warns about vessel files in data/imo and data/mmsi whose names only differ by case, e.g. NAME_123.csv and Name_123.csv.
+/// They are different files on case sensitive file systems, e.g. on Linux, but the same file on case insensitive ones, e.g. on macOS and Windows,
+/// so one would overwrite the other if the data folder is copied there. --compact merges them
+fn warn_about_case_variant_files() {
+    for folder in ["data/imo", "data/mmsi"] {
+        let files = match get_vessel_files(std::path::Path::new(folder)) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Error looking for files in {} whose names only differ by case: {}", folder, e);
+                continue;
+            }
+        };
+
+        // Group the files by their lowercase path
+        let mut variants: std::collections::BTreeMap<String, Vec<String>> = std::collections::BTreeMap::new();
+        for file in files {
+            variants.entry(file.to_string_lossy().to_lowercase()).or_default().push(file.display().to_string());
+        }
+        for (_, mut paths) in variants {
+            if paths.len() > 1 {
+                paths.sort();
+                println!("Warning: {} only differ by case and are the same file on case insensitive file systems, e.g. on macOS and Windows. Run with --compact to merge them.", paths.join(", "));
+            }
+        }
+    }
+}
+
+/// Removes an incomplete last line from a vessel file, i.e. anything after the last newline, which is left behind if the program was killed while writing.
+/// Without this the last record can not be read and no more records would be saved to the file. The removed text is quarantined so nothing is lost.
+/// If not even the headers are complete the file is made again with headers. Returns true if the file was repaired
+fn repair_incomplete_last_line(file_path: &std::path::Path, settings: &Settings) -> Result<bool, Box<dyn std::error::Error>> {
+    // Gzip compressed files are always written whole to a temporary file first, so they are never left half written
+    if is_gzip_file(file_path) {
+        return Ok(false);
+    }
+
+    // Nothing to repair if the file is empty or ends with a newline, only the last byte is read so this stays cheap
+    let mut file = fs::File::open(file_path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last_byte = [0u8; 1];
+    file.seek(io::SeekFrom::End(-1))?;
+    file.read_exact(&mut last_byte)?;
+    if last_byte[0] == b'\n' {
+        return Ok(false);
+    }
+
+    // Cut the file after the last newline, or start over with headers if there is none
+    let contents = fs::read(file_path)?;
+    let complete_length = contents.iter().rposition(|b| *b == b'\n').map(|i| i + 1);
+    let incomplete_line = String::from_utf8_lossy(&contents[complete_length.unwrap_or(0)..]).to_string();
+    match complete_length {
+        Some(length) => fs::OpenOptions::new().write(true).open(file_path)?.set_len(length as u64)?,
+        None => make_empty_csv_file(file_path, settings)?,
+    }
+    println!("Repaired {}: removed an incomplete last line left by an interrupted write, it has been moved to {}", file_path.display(), QUARANTINE_FILE);
+    quarantine_record(file_path.display().to_string().as_str(), "Incomplete last line", incomplete_line.as_str());
+
+    // Return true since the file was repaired
+    return Ok(true);
+}
+
+/// Converts a vessel file written by version 1 of the collector, which wrote the headers of the mmsi files with semicolons but the records with commas, to semicolons throughout.
+/// Without this the TSTAMP of the last record can not be found and no more records would be saved to the file.
+/// If the records can not all be read with commas the file is rolled over instead so a new file is started. Returns true if the file was converted or rolled over
+fn convert_legacy_delimiter(file_path: &std::path::Path, settings: &Settings) -> Result<bool, Box<dyn std::error::Error>> {
+    // Gzip compressed files were never written by version 1
+    if is_gzip_file(file_path) {
+        return Ok(false);
+    }
+
+    // Only the headers and first record are read to check the file, the file is a legacy file if the first record has a single value under several headers
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .flexible(true)
+        .from_path(file_path)?;
+    let headers = reader.headers()?.clone();
+    match reader.records().next() {
+        Some(Ok(record)) if headers.len() > 1 && record.len() == 1 => {},
+        _ => return Ok(false),
+    }
+
+    // Read the records with commas, they must all have a value for each header
+    let contents = fs::read_to_string(file_path)?;
+    let mut records: Vec<csv::StringRecord> = Vec::new();
+    let mut readable = true;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(contents.split_once('\n').map(|(_, rest)| rest).unwrap_or_default().as_bytes());
+    for result in reader.records() {
+        match result {
+            Ok(record) if record.len() == headers.len() => records.push(record),
+            _ => {
+                readable = false;
+                break;
+            }
+        }
+    }
+    if !readable {
+        println!("{} was written by an older version with commas between the values, but not all records could be read with commas. Rolling it over so a new file is started.", file_path.display());
+        roll_over_file(file_path, settings)?;
+        return Ok(true);
+    }
+
+    // Write the file again with semicolons, keeping the headers as they are
+    let mut wtr = make_csv_writer_builder(settings).from_writer(Vec::new());
+    wtr.write_record(&headers)?;
+    for record in &records {
+        wtr.write_record(record)?;
+    }
+    wtr.flush()?;
+    replace_file_contents(file_path, wtr.get_ref())?;
+    println!("Converted {}: it was written by an older version with commas between the values, {} records now have semicolons between the values.", file_path.display(), records.len());
+
+    // Return true since the file was converted
+    return Ok(true);
+}
+
+/// Makes the path of the metadata file of a vessel file, e.g. NAME_123.csv -> NAME_123.meta.json
+fn make_metadata_path(file_path: &std::path::Path) -> std::path::PathBuf {
+    return file_path.with_extension("meta.json");
+}
+
+/// Writes a new metadata file for a vessel file with the formats in the current settings
+fn write_file_metadata(file_path: &std::path::Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let now = time::UtcDateTime::now().unix_timestamp();
+    let metadata = FileMetadata {
+        output_format: settings.output_format.clone(),
+        data_value_format: settings.data_value_format,
+        normalize_sog_cog: settings.normalize_sog_cog,
+        collector_version: env!("CARGO_PKG_VERSION").to_string(),
+        first_collected: now,
+        last_collected: now,
+    };
+    fs::write(make_metadata_path(file_path), serde_json::to_string_pretty(&metadata)?)?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Updates the last collection time in the metadata file of a vessel file after a record was saved, or writes a new one if there is none.
+/// Only written once a day so saving a record stays cheap.
+/// Warns if the formats in the metadata file are not the ones in the current settings, since the file then has records in more than one format
+fn update_file_metadata(file_path: &std::path::Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Write a new metadata file if there is none, e.g. for new files or files made before file_metadata was set
+    let metadata_path = make_metadata_path(file_path);
+    let contents = match fs::read_to_string(&metadata_path) {
+        Ok(c) => c,
+        Err(_) => return write_file_metadata(file_path, settings),
+    };
+    let mut metadata: FileMetadata = serde_json::from_str(&contents)?;
+
+    // Nothing to do if it was already updated today
+    let now = time::UtcDateTime::now();
+    if time::UtcDateTime::from_unix_timestamp(metadata.last_collected)?.date() == now.date() {
+        return Ok(());
+    }
+
+    // Warn if the formats have changed since the file was made
+    if metadata.data_value_format != settings.data_value_format || metadata.normalize_sog_cog != settings.normalize_sog_cog {
+        println!("Warning: {} was made with data_value_format {} and normalize_sog_cog {} but records are now saved with data_value_format {} and normalize_sog_cog {}. Move the file away so each file has one format.", file_path.display(), metadata.data_value_format, metadata.normalize_sog_cog, settings.data_value_format, settings.normalize_sog_cog);
+    }
+
+    // Update last collection time
+    metadata.last_collected = now.unix_timestamp();
+    fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Moves the metadata file of a vessel file along with it if there is one
+fn move_file_metadata(from: &std::path::Path, to: &std::path::Path) -> Result<(), io::Error> {
+    let metadata_path = make_metadata_path(from);
+    if metadata_path.exists() {
+        fs::rename(metadata_path, make_metadata_path(to))?;
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Appends records to the combined file with all vessels, creating it with headers if it does not exist
+fn append_to_combined_file(file_path: &std::path::Path, rows: &[(&VesselInfo, DerivedColumns)], settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Check if file exists, if not create it and its folder
+    if !file_path.exists() {
+        match file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent)?,
+            _ => {}
+        }
+        make_empty_csv_file(file_path, settings)?;
+    } else {
+        repair_incomplete_last_line(file_path, settings)?;
+    }
+
+    // Check the file has the same columns as the rows that are about to be written
+    let headers: csv::StringRecord = csv::ReaderBuilder::new().delimiter(b';').from_path(file_path)?.headers()?.iter().map(|h| settings.standard_header(h)).collect();
+    check_headers(&headers, file_path, settings)?;
+
+    // Append data to file
+    let mut wtr = make_csv_writer_builder(settings)
+        .from_writer(fs::OpenOptions::new()
+            .append(true)
+            .open(file_path)?);
+    write_records_to_file(&mut wtr, rows, settings)?;
+    if settings.fsync_after_write {
+        sync_file(file_path)?;
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Saves the data to a new part file in the folder of the day of each record's timestamp in PARQUET_FOLDER, e.g. data/parquet/2025-01-31/1738281600.parquet.
+/// Parquet files can not be appended to, so each cycle writes its own part file instead of writing the whole day's file again.
+/// Records that are not newer than the latest record of the same ship in the day's files are skipped as duplicates, like in the vessel files.
+/// Returns the records that were written and adds the records skipped as duplicates to stats. Records without an identifier are counted by save_data
+fn save_data_to_parquet<'a>(data: &'a [VesselInfo], stats: &mut SaveStats, settings: &Settings) -> Result<Vec<&'a VesselInfo>, Box<dyn std::error::Error>> {
+    let mut written: Vec<&VesselInfo> = Vec::new();
+
+    // Check if parquet folder exists, if not create it
+    if !std::path::Path::new(PARQUET_FOLDER).exists() {
+        fs::create_dir(PARQUET_FOLDER)?;
+    }
+
+    // Group records by the day of their timestamp, sorted by timestamp so they can be checked for duplicates in order
+    let mut days: std::collections::BTreeMap<String, Vec<&VesselInfo>> = std::collections::BTreeMap::new();
+    for vessel in data {
+        let datetime = time::UtcDateTime::from_unix_timestamp(vessel.timestamp as i64)?;
+        days.entry(format_date(datetime)).or_default().push(vessel);
+    }
+
+    // Save each day's records
+    for (day, mut vessels) in days {
+        vessels.sort_by_key(|v| v.timestamp);
+        let day_folder = std::path::Path::new(PARQUET_FOLDER).join(day.as_str());
+
+        // Get the latest timestamp of each ship in the day's part files, and in the single file per day written by older versions
+        let mut day_files: Vec<std::path::PathBuf> = Vec::new();
+        let old_day_file = std::path::Path::new(PARQUET_FOLDER).join(format!("{}.parquet", day));
+        if old_day_file.exists() {
+            day_files.push(old_day_file);
+        }
+        if day_folder.exists() {
+            for entry in fs::read_dir(&day_folder)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) == Some("parquet") {
+                    day_files.push(path);
+                }
+            }
+        }
+        let mut last_timestamps: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+        for day_file in &day_files {
+            read_parquet_last_timestamps(day_file, &mut last_timestamps, settings)?;
+        }
+
+        // Skip duplicates, records without an identifier and records that are not newer than the latest one of the same ship
+        let mut new_records: Vec<&VesselInfo> = Vec::new();
+        for vessel in vessels {
+            if vessel.imo == 0 && vessel.mmsi == 0 {
+                continue;
+            }
+            let last = last_timestamps.entry(get_ship_key(vessel, settings)).or_default();
+            if vessel.timestamp <= *last {
+                stats.deduped += 1;
+                continue;
+            }
+            *last = vessel.timestamp;
+            new_records.push(vessel);
+        }
+        if new_records.is_empty() {
+            continue;
+        }
+
+        // Write the new records to a temporary file, then move it to the next free part file name so a half written part file is never read
+        fs::create_dir_all(&day_folder)?;
+        let now = time::UtcDateTime::now().unix_timestamp();
+        let mut file_path = day_folder.join(format!("{}.parquet", now));
+        let mut number: u32 = 1;
+        while file_path.exists() {
+            file_path = day_folder.join(format!("{}_{}.parquet", now, number));
+            number += 1;
+        }
+        let temp_path = file_path.with_extension("parquet.tmp");
+        let props = parquet::file::properties::WriterProperties::builder()
+            .set_compression(parquet::basic::Compression::SNAPPY)
+            .build();
+        let schema = std::sync::Arc::new(make_parquet_schema());
+        let mut writer = parquet::arrow::ArrowWriter::try_new(fs::File::create(&temp_path)?, schema.clone(), Some(props))?;
+        writer.write(&make_parquet_batch(&new_records, schema)?)?;
+        writer.close()?;
+        fs::rename(&temp_path, &file_path)?;
+        if settings.fsync_after_write {
+            sync_file(&file_path)?;
+        }
+
+        written.extend(new_records);
+    }
+
+    // Return written records
+    return Ok(written);
+}
+
+/// Makes the schema of the Parquet files, with the same column names as the vessel files and types from VesselInfo
+/// Latitude and longitude are numbers instead of strings, and are null like COG and SOG if unknown
+fn make_parquet_schema() -> arrow_schema::Schema {
+    let fields: Vec<arrow_schema::Field> = STANDARD_COLUMNS.iter().map(|column| {
+        let (data_type, nullable) = match *column {
+            "CALLSIGN" | "DEST" | "DEVICE" | "NAME" | "NAVSTAT" | "ROT" => (arrow_schema::DataType::Utf8, false),
+            "COG" | "LATITUDE" | "LONGITUDE" | "SOG" => (arrow_schema::DataType::Float64, true),
+            "PAC" => (arrow_schema::DataType::UInt8, false),
+            _ => (arrow_schema::DataType::UInt64, false),
+        };
+        return arrow_schema::Field::new(*column, data_type, nullable);
+    }).collect();
+    return arrow_schema::Schema::new(fields);
+}
+
+/// Makes a record batch with the schema from make_parquet_schema from a list of records
+fn make_parquet_batch(vessels: &[&VesselInfo], schema: std::sync::Arc<arrow_schema::Schema>) -> Result<arrow_array::RecordBatch, Box<dyn std::error::Error>> {
+    let mut columns: Vec<arrow_array::ArrayRef> = Vec::new();
+    for field in schema.fields() {
+        let u64_column = |value: fn(&VesselInfo) -> u64| -> arrow_array::ArrayRef {
+            return std::sync::Arc::new(arrow_array::UInt64Array::from_iter_values(vessels.iter().map(|v| value(v))));
+        };
+        let string_column = |value: fn(&VesselInfo) -> &str| -> arrow_array::ArrayRef {
+            return std::sync::Arc::new(arrow_array::StringArray::from_iter_values(vessels.iter().map(|v| value(v))));
+        };
+        let f64_column = |value: fn(&VesselInfo) -> Option<f64>| -> arrow_array::ArrayRef {
+            return std::sync::Arc::new(arrow_array::Float64Array::from_iter(vessels.iter().map(|v| value(v))));
+        };
+        let column = match field.name().as_str() {
+            "A" => u64_column(|v| v.a),
+            "B" => u64_column(|v| v.b),
+            "C" => u64_column(|v| v.c),
+            "CALLSIGN" => string_column(|v| v.callsign.as_str()),
+            "COG" => f64_column(|v| v.cog),
+            "D" => u64_column(|v| v.d),
+            "DEST" => string_column(|v| v.dest.as_str()),
+            "DRAUGHT" => u64_column(|v| v.draught),
+            "DEVICE" => string_column(|v| v.device.as_str()),
+            "ETA" => u64_column(|v| v.eta),
+            "HEADING" => u64_column(|v| v.heading),
+            "IMO" => u64_column(|v| v.imo),
+            "LATITUDE" => f64_column(|v| v.latitude.trim().parse().ok()),
+            "LONGITUDE" => f64_column(|v| v.longitude.trim().parse().ok()),
+            "MMSI" => u64_column(|v| v.mmsi),
+            "NAME" => string_column(|v| v.name.as_str()),
+            "NAVSTAT" => string_column(|v| v.navstat.as_str()),
+            "PAC" => std::sync::Arc::new(arrow_array::UInt8Array::from_iter_values(vessels.iter().map(|v| v.pac))),
+            "ROT" => string_column(|v| v.rot.as_str()),
+            "SOG" => f64_column(|v| v.sog),
+            "TSTAMP" => u64_column(|v| v.timestamp),
+            "TYPE" => u64_column(|v| v.vessel_type),
+            other => return Err(Box::from(format!("No value for Parquet column {}", other))),
+        };
+        columns.push(column);
+    }
+
+    // Return record batch
+    return Ok(arrow_array::RecordBatch::try_new(schema, columns)?);
+}
+
+/// Reads the latest timestamp of each ship in a Parquet file into last_timestamps, keeping the later one if a ship is already in it
+/// Only the IMO, MMSI and TSTAMP columns are read so this stays cheap however many part files a day has
+fn read_parquet_last_timestamps(file_path: &std::path::Path, last_timestamps: &mut std::collections::HashMap<String, u64>, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let builder = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(fs::File::open(file_path)?)?;
+    let mask = parquet::arrow::ProjectionMask::columns(builder.parquet_schema(), ["IMO", "MMSI", "TSTAMP"]);
+    for batch in builder.with_projection(mask).build()? {
+        let batch = batch?;
+        let imo = get_parquet_u64_column(&batch, "IMO")?;
+        let mmsi = get_parquet_u64_column(&batch, "MMSI")?;
+        let timestamp = get_parquet_u64_column(&batch, "TSTAMP")?;
+        for row in 0..batch.num_rows() {
+            let last = last_timestamps.entry(make_ship_key(imo.value(row), mmsi.value(row), settings)).or_default();
+            *last = (*last).max(timestamp.value(row));
+        }
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Gets a column of whole numbers from a record batch read from a Parquet file by name
+fn get_parquet_u64_column<'a>(batch: &'a arrow_array::RecordBatch, name: &str) -> Result<&'a arrow_array::UInt64Array, Box<dyn std::error::Error>> {
+    match batch.column_by_name(name).and_then(|c| c.as_any().downcast_ref::<arrow_array::UInt64Array>()) {
+        Some(column) => return Ok(column),
+        None => return Err(Box::from(format!("Missing or invalid {} column in Parquet file", name))),
+    }
+}
+
+/// Writes the headers of the vessel files to stdout for --stdout, with the columns, aliases and quote style from the settings
+fn write_headers_to_stdout(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = make_csv_writer_builder(settings).from_writer(io::stdout());
+    wtr.write_record(make_headers(settings).iter().map(|h| settings.header_alias(h)))?;
+    wtr.flush()?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Writes records to stdout as CSV for --stdout instead of saving them, in the same format as the vessel files.
+/// Records that are not newer than the last one written for the same ship are skipped, using last_timestamps since there is no file to check.
+/// Columns that depend on the previous record in the file, e.g. CUM_DIST_NM, are left empty. Returns the records that were written and the number of records written and skipped
+fn write_data_to_stdout<'a>(data: &'a [VesselInfo], last_timestamps: &mut std::collections::HashMap<String, u64>, labels: &std::collections::HashMap<String, String>, settings: &Settings) -> Result<(Vec<&'a VesselInfo>, SaveStats), Box<dyn std::error::Error>> {
+    let mut written: Vec<&VesselInfo> = Vec::new();
+    let mut stats = SaveStats::default();
+    let mut wtr = make_csv_writer_builder(settings).from_writer(io::stdout());
+
+    // Sort by timestamp so each ship's records are written in order
+    let mut sorted_data: Vec<&VesselInfo> = data.iter().collect();
+    sorted_data.sort_by_key(|v| v.timestamp);
+
+    for vessel in sorted_data {
+        // Skip duplicates
+        let last = last_timestamps.entry(get_ship_key(vessel, settings)).or_default();
+        if vessel.timestamp <= *last {
+            stats.deduped += 1;
+            continue;
+        }
+        *last = vessel.timestamp;
+
+        // Write record
+        let mut derived = make_derived_columns(vessel, &LastRecord { headers: csv::StringRecord::new(), record: None, count: 0 }, settings);
+        derived.label = labels.get(&format!("IMO {}", vessel.imo)).or(labels.get(&format!("MMSI {}", vessel.mmsi))).cloned();
+        write_data_to_file(&mut wtr, vessel, &derived, settings)?;
+        written.push(vessel);
+    }
+    stats.written = written.len();
+
+    // Return written records
+    return Ok((written, stats));
+}
+
+/// Gets the headers, the last record and the number of records of a vessel file
+/// Aliased headers are turned back into the standard header names
+fn get_last_record(file_path: &std::path::Path, settings: &Settings) -> Result<LastRecord, Box<dyn std::error::Error>> {
+    // Make csv file reader
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .from_reader(open_file_reader(file_path)?);
+
+    // Get headers
+    let headers: csv::StringRecord = reader.headers()?.iter().map(|h| settings.standard_header(h)).collect();
+
+    // Get last line of file and count the records on the way
+    let mut count: usize = 0;
+    let mut last = None;
+    for result in reader.into_records() {
+        count += 1;
+        last = Some(result);
+    }
+    let record = match last {
+        Some(Ok(record)) => Some(record),
+        Some(Err(e)) => {
+            return Err(Box::from(format!("Error reading record from CSV file: {}", e)));
+        }
+        None => None, // File has no records
+    };
+
+    // Return last record
+    return Ok(LastRecord { headers, record, count });
+}
+
+/// Works out the values of the optional columns that are not in the response, e.g. the ones that depend on the last record in the vessel file
+fn make_derived_columns(vessel: &VesselInfo, last_record: &LastRecord, settings: &Settings) -> DerivedColumns {
+    let mut derived = DerivedColumns::default();
+
+    // Cumulative distance, continues from the total in the last record or starts at 0
+    if settings.cumulative_distance {
+        let mut total: f64 = last_record.get("CUM_DIST_NM").and_then(|v| v.parse().ok()).unwrap_or(0.0);
+
+        // Only add to the total if both positions are known
+        match get_distance_from_last_record_nm(vessel, last_record, settings) {
+            Some(distance) => total += distance,
+            None => {}
+        }
+
+        derived.cum_dist_nm = Some(total);
+    }
+
+    // Time the record is saved at
+    let now = time::UtcDateTime::now().unix_timestamp();
+    if settings.record_receive_time {
+        derived.recv_tstamp = Some(now);
+    }
+
+    // Age of the record, unknown if the record has no timestamp
+    if settings.record_age && vessel.timestamp > 0 {
+        derived.age_secs = Some(now - vessel.timestamp as i64);
+    }
+
+    // Sequence number, counts on from the last record, or from the number of records if it has no sequence number.
+    // Only records saved to a vessel file are numbered, the last record used for --stdout and the unidentified file has no headers
+    if settings.sequence_column && !last_record.headers.is_empty() {
+        derived.seq = Some(last_record.get("SEQ").and_then(|s| s.parse::<u64>().ok()).map(|s| s + 1).unwrap_or(last_record.count as u64));
+    }
+
+    // Suspect position, unknown if either record has no position or timestamp
+    match settings.max_implied_speed_knots {
+        Some(max_speed) => derived.suspect = get_implied_speed_knots(vessel, last_record, settings).map(|speed| speed > max_speed),
+        None => {}
+    }
+
+    // Return derived columns
+    return derived;
+}
+
+/// Works out the speed in knots needed to get from the last record in the vessel file to a new record, from the haversine distance and the time between them
+/// Returns None if either record has no position or timestamp, or the new record is not newer
+fn get_implied_speed_knots(vessel: &VesselInfo, last_record: &LastRecord, settings: &Settings) -> Option<f64> {
+    // Get distance and previous timestamp
+    let distance = get_distance_from_last_record_nm(vessel, last_record, settings)?;
+    let previous_timestamp: u64 = last_record.get("TSTAMP")?.parse().ok()?;
+    if previous_timestamp == 0 || vessel.timestamp <= previous_timestamp {
+        return None;
+    }
+
+    // Return speed
+    let hours = (vessel.timestamp - previous_timestamp) as f64 / 3600.0;
+    return Some(distance / hours);
+}
+
+/// Works out the distance in nautical miles from the position in the last record in the vessel file to the position of a new record
+/// Returns None if either position is unknown
+fn get_distance_from_last_record_nm(vessel: &VesselInfo, last_record: &LastRecord, settings: &Settings) -> Option<f64> {
+    let previous_lat = last_record.get("LATITUDE").and_then(|v| coordinate_to_degrees(v, 90.0, settings.data_value_format))?;
+    let previous_lon = last_record.get("LONGITUDE").and_then(|v| coordinate_to_degrees(v, 180.0, settings.data_value_format))?;
+    let lat = coordinate_to_degrees(vessel.latitude.as_str(), 90.0, settings.data_value_format)?;
+    let lon = coordinate_to_degrees(vessel.longitude.as_str(), 180.0, settings.data_value_format)?;
+    return Some(haversine_nm(previous_lat, previous_lon, lat, lon));
+}
+
+/// Checks if a new record is a near duplicate of the last record in the vessel file, i.e. within dedup_window_secs of it and at essentially the same position
+/// Always false if dedup_window_secs is not set or 0, or either position is unknown
+fn is_near_duplicate(vessel: &VesselInfo, last_record: &LastRecord, settings: &Settings) -> Result<bool, Box<dyn std::error::Error>> {
+    let window = settings.dedup_window_secs.unwrap_or(0);
+    if window == 0 || vessel.timestamp > last_record.timestamp()? + window {
+        return Ok(false);
+    }
+    match get_distance_from_last_record_nm(vessel, last_record, settings) {
+        Some(distance) => return Ok(distance <= DEDUP_DISTANCE_NM),
+        None => return Ok(false),
+    }
+}
+
+/// Rolls a vessel file over by renaming it to the next free numbered filename, e.g. NAME_123.csv -> NAME_123.001.csv
+/// and creating a fresh file with headers in its place so new data is always appended to the original filename
+fn roll_over_file(file_path: &std::path::Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Find the next free rollover number
+    let mut number: u32 = 1;
+    let mut rolled_path = make_rollover_path(file_path, number)?;
+    while rolled_path.exists() {
+        number += 1;
+        rolled_path = make_rollover_path(file_path, number)?;
+    }
+
+    // Rename the current file and its metadata file and start a fresh one
+    fs::rename(file_path, &rolled_path)?;
+    move_file_metadata(file_path, &rolled_path)?;
+    make_empty_csv_file(file_path, settings)?;
+    println!("Rolled over {} to {}", file_path.display(), rolled_path.display());
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Makes the path of a rolled over vessel file, e.g. NAME_123.csv with number 1 -> NAME_123.001.csv
+fn make_rollover_path(file_path: &std::path::Path, number: u32) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let stem = match get_vessel_file_stem(file_path) {
+        Some(s) => s,
+        None => return Err(Box::from(format!("Invalid file path: {}", file_path.display()))),
+    };
+    let extension = if is_gzip_file(file_path) { "csv.gz" } else { "csv" };
+    return Ok(file_path.with_file_name(format!("{}.{:03}.{}", stem, number, extension)));
+}
+
+/// Makes a new empty .csv file with the correct headers in the correct order
+fn make_empty_csv_file(file_path: &std::path::Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Sanity check the file_path ends with ".csv" or ".csv.gz"
+    if get_vessel_file_stem(file_path).is_none() {
+        return Err(Box::from("File path must end with .csv or .csv.gz"));
+    }
+
+    // Write headers, using the aliases if set
+    let mut wtr = make_csv_writer_builder(settings).from_writer(Vec::new());
+    wtr.write_record(make_headers(settings).iter().map(|h| settings.header_alias(h)))?;
+    wtr.flush()?;
+    replace_file_contents(file_path, wtr.get_ref())?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Checks the headers of a file, with aliases changed back to the standard headers, are the columns written with the current settings in the same order
+/// Returns an error instead of letting rows be written misaligned with the headers, e.g. when one optional column was turned off and another one turned on
+fn check_headers(headers: &csv::StringRecord, file_path: &std::path::Path, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let expected = make_headers(settings);
+    if headers.iter().ne(expected.iter().copied()) {
+        return Err(Box::from(format!("{} has the columns {} but {} would be written with the current settings. Change the optional column settings back, or move the file away so a new one is started", file_path.display(), headers.iter().collect::<Vec<&str>>().join(", "), expected.join(", "))));
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Makes a CSV writer builder for the vessel files with the separator and quote style from the settings
+fn make_csv_writer_builder(settings: &Settings) -> csv::WriterBuilder {
+    let mut builder = csv::WriterBuilder::new();
+    builder.delimiter(b';');
+    match settings.quote_style.as_str() {
+        "always" => builder.quote_style(csv::QuoteStyle::Always),
+        "never" => builder.quote_style(csv::QuoteStyle::Never),
+        _ => builder.quote_style(csv::QuoteStyle::Necessary),
+    };
+    return builder;
+}
+
+/// Makes the headers of the vessel files in the correct order
+/// Optional columns are added at the end so the standard columns always keep their position
+fn make_headers(settings: &Settings) -> Vec<&'static str> {
+    let mut headers: Vec<&str> = STANDARD_COLUMNS.to_vec();
+    if settings.decode_eta {
+        headers.push("ETA_DECODED");
+    }
+    if settings.cumulative_distance {
+        headers.push("CUM_DIST_NM");
+    }
+    if settings.decode_rot {
+        headers.push("ROT_DPM");
+    }
+    if settings.record_receive_time {
+        headers.push("RECV_TSTAMP");
+    }
+    if settings.label_column {
+        headers.push("LABEL");
+    }
+    if settings.record_age {
+        headers.push("AGE_SECS");
+    }
+    if settings.source_tag.is_some() {
+        headers.push("SOURCE");
+    }
+    if settings.flags_suspect_positions() {
+        headers.push("SUSPECT");
+    }
+    if settings.wkt_column {
+        headers.push("GEOM_WKT");
+    }
+    if settings.flag_column {
+        headers.push("FLAG");
+    }
+    if settings.sequence_column {
+        headers.push("SEQ");
+    }
+    if settings.keep_raw_columns && settings.normalize_sog_cog {
+        headers.push("SOG_RAW");
+        headers.push("COG_RAW");
+    }
+    if settings.keep_raw_columns && settings.normalize_names {
+        headers.push("NAME_RAW");
+    }
+
+    // Return headers
+    return headers;
+}
+
+/// Writes data to file given a csv writer
+fn write_data_to_file<W: io::Write>(wtr: &mut csv::Writer<W>, vessel: &VesselInfo, derived: &DerivedColumns, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Write record
+    wtr.write_record(make_record_values(vessel, derived, settings)?)?;
+    wtr.flush()?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Writes several records and flushes once after the last one, see write_data_to_file
+fn write_records_to_file<W: io::Write>(wtr: &mut csv::Writer<W>, rows: &[(&VesselInfo, DerivedColumns)], settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    for (vessel, derived) in rows {
+        wtr.write_record(make_record_values(vessel, derived, settings)?)?;
+    }
+    wtr.flush()?;
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Makes the values of a record with a value for each header, so the values are always in the same order as the headers in make_empty_csv_file
+fn make_record_values(vessel: &VesselInfo, derived: &DerivedColumns, settings: &Settings) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut record: Vec<String> = Vec::new();
+    for column in make_headers(settings) {
+        match get_column_value(column, vessel, derived, settings) {
+            Some(value) => record.push(value),
+            None => return Err(Box::from(format!("No value for column {}", column))),
+        }
+    }
+
+    // Return record
+    return Ok(record);
+}
+
+/// Gets the value to write for a column of the vessel files
+/// Returns None if the column is unknown
+fn get_column_value(column: &str, vessel: &VesselInfo, derived: &DerivedColumns, settings: &Settings) -> Option<String> {
+    // Number of decimal places for float columns
+    let precision = settings.coordinate_precision.unwrap_or(COORDINATE_PRECISION_DEFAULT);
+
+    let value = match column {
+        "A" => format_zero_as_unknown(vessel.a, settings),
+        "B" => format_zero_as_unknown(vessel.b, settings),
+        "C" => format_zero_as_unknown(vessel.c, settings),
+        "CALLSIGN" => vessel.callsign.clone(),
+        "COG" => vessel.cog.map(|v| round_to_precision(v, precision)).unwrap_or_default(),
+        "D" => format_zero_as_unknown(vessel.d, settings),
+        "DEST" => vessel.dest.clone(),
+        "DRAUGHT" => format_zero_as_unknown(vessel.draught, settings),
+        "DEVICE" => vessel.device.clone(),
+        "ETA" => vessel.eta.to_string(),
+        "HEADING" => vessel.heading.to_string(),
+        "IMO" => vessel.imo.to_string(),
+        "LATITUDE" => round_float_string(vessel.latitude.as_str(), precision),
+        "LONGITUDE" => round_float_string(vessel.longitude.as_str(), precision),
+        "MMSI" => vessel.mmsi.to_string(),
+        "NAME" => vessel.name.clone(),
+        "NAVSTAT" => vessel.navstat.clone(),
+        "PAC" => vessel.pac.to_string(),
+        "ROT" => vessel.rot.clone(),
+        "SOG" => vessel.sog.map(|v| round_to_precision(v, precision)).unwrap_or_default(),
+        "TSTAMP" => vessel.timestamp.to_string(),
+        "TYPE" => format_zero_as_unknown(vessel.vessel_type, settings),
+        // Optional columns
+        "ETA_DECODED" => decode_eta(vessel.eta).unwrap_or_default(),
+        "CUM_DIST_NM" => derived.cum_dist_nm.map(|d| round_to_precision(d, precision)).unwrap_or_default(),
+        "ROT_DPM" => decode_rot(vessel.rot.as_str()).map(|r| round_to_precision(r, precision)).unwrap_or_default(),
+        "RECV_TSTAMP" => derived.recv_tstamp.map(|t| t.to_string()).unwrap_or_default(),
+        "LABEL" => derived.label.clone().unwrap_or_default(),
+        "AGE_SECS" => derived.age_secs.map(|a| a.to_string()).unwrap_or_default(),
+        "SOURCE" => settings.source_tag.clone().unwrap_or_default(),
+        "SUSPECT" => derived.suspect.map(|s| if s { "1" } else { "0" }.to_string()).unwrap_or_default(),
+        "GEOM_WKT" => make_wkt_point(vessel, settings).unwrap_or_default(),
+        "FLAG" => mmsi_flag(vessel.mmsi).unwrap_or_default().to_string(),
+        "SEQ" => derived.seq.map(|s| s.to_string()).unwrap_or_default(),
+        "SOG_RAW" => vessel.raw_sog.clone(),
+        "COG_RAW" => vessel.raw_cog.clone(),
+        "NAME_RAW" => vessel.raw_name.clone(),
+        _ => return None,
+    };
+
+    // Return value
+    return Some(value);
+}
+
+/// Formats a value that is 0 when it is unknown, e.g. the dimensions, draught and type, as an empty string if it is 0 and unknown_as_empty is set
+fn format_zero_as_unknown(value: u64, settings: &Settings) -> String {
+    if value == 0 && settings.unknown_as_empty {
+        return String::new();
+    }
+    return value.to_string();
+}
+
+/// Makes a WKT point of a record's position in degrees, e.g. "POINT(5.2 60.1)", with the coordinate precision from the settings
+/// Returns None if the latitude or longitude is unknown
+fn make_wkt_point(vessel: &VesselInfo, settings: &Settings) -> Option<String> {
+    let precision = settings.coordinate_precision.unwrap_or(COORDINATE_PRECISION_DEFAULT);
+    let lat = coordinate_to_degrees(vessel.latitude.as_str(), 90.0, settings.data_value_format)?;
+    let lon = coordinate_to_degrees(vessel.longitude.as_str(), 180.0, settings.data_value_format)?;
+    return Some(format!("POINT({} {})", round_to_precision(lon, precision), round_to_precision(lat, precision)));
+}
+
+/// Gets the flag state of a ship from the Maritime Identification Digits (MID) of its MMSI number, e.g. 257123456 -> "Norway"
+/// The MID is the first three digits of a ship's MMSI, or the digits after the 111, 98 or 99 prefix of SAR aircraft, craft associated with a ship and aids to navigation.
+/// Based on the ITU table of Maritime Identification Digits. Returns None for MMSI numbers that are not 9 digits, have no MID, e.g. 970 SART numbers, or have an unassigned MID
+fn mmsi_flag(mmsi: u64) -> Option<&'static str> {
+    // Only 9 digit MMSI numbers have a MID in a known place
+    if !(100_000_000..1_000_000_000).contains(&mmsi) {
+        return None;
+    }
+    let mid = if mmsi / 1_000_000 == 111 {
+        mmsi / 1_000 % 1_000
+    } else if mmsi / 10_000_000 == 98 || mmsi / 10_000_000 == 99 {
+        mmsi / 10_000 % 1_000
+    } else {
+        mmsi / 1_000_000
+    };
+
+    let flag = match mid {
+        201 => "Albania",
+        202 => "Andorra",
+        203 => "Austria",
+        204 => "Azores",
+        205 => "Belgium",
+        206 => "Belarus",
+        207 => "Bulgaria",
+        208 => "Vatican City",
+        209 | 210 | 212 => "Cyprus",
+        211 | 218 => "Germany",
+        213 => "Georgia",
+        214 => "Moldova",
+        215 | 229 | 248 | 249 | 256 => "Malta",
+        216 => "Armenia",
+        219 | 220 => "Denmark",
+        224 | 225 => "Spain",
+        226..=228 => "France",
+        230 => "Finland",
+        231 => "Faroe Islands",
+        232..=235 => "United Kingdom",
+        236 => "Gibraltar",
+        237 | 239..=241 => "Greece",
+        238 => "Croatia",
+        242 => "Morocco",
+        243 => "Hungary",
+        244..=246 => "Netherlands",
+        247 => "Italy",
+        250 => "Ireland",
+        251 => "Iceland",
+        252 => "Liechtenstein",
+        253 => "Luxembourg",
+        254 => "Monaco",
+        255 => "Madeira",
+        257..=259 => "Norway",
+        261 => "Poland",
+        262 => "Montenegro",
+        263 => "Portugal",
+        264 => "Romania",
+        265 | 266 => "Sweden",
+        267 => "Slovakia",
+        268 => "San Marino",
+        269 => "Switzerland",
+        270 => "Czech Republic",
+        271 => "Turkey",
+        272 => "Ukraine",
+        273 => "Russia",
+        274 => "North Macedonia",
+        275 => "Latvia",
+        276 => "Estonia",
+        277 => "Lithuania",
+        278 => "Slovenia",
+        279 => "Serbia",
+        301 => "Anguilla",
+        303 => "Alaska",
+        304 | 305 => "Antigua and Barbuda",
+        306 => "Dutch Caribbean",
+        307 => "Aruba",
+        308 | 309 | 311 => "Bahamas",
+        310 => "Bermuda",
+        312 => "Belize",
+        314 => "Barbados",
+        316 => "Canada",
+        319 => "Cayman Islands",
+        321 => "Costa Rica",
+        323 => "Cuba",
+        325 => "Dominica",
+        327 => "Dominican Republic",
+        329 => "Guadeloupe",
+        330 => "Grenada",
+        331 => "Greenland",
+        332 => "Guatemala",
+        334 => "Honduras",
+        336 => "Haiti",
+        338 | 366..=369 => "United States",
+        339 => "Jamaica",
+        341 => "Saint Kitts and Nevis",
+        343 => "Saint Lucia",
+        345 => "Mexico",
+        347 => "Martinique",
+        348 => "Montserrat",
+        350 => "Nicaragua",
+        351..=357 | 370..=374 => "Panama",
+        358 => "Puerto Rico",
+        359 => "El Salvador",
+        361 => "Saint Pierre and Miquelon",
+        362 => "Trinidad and Tobago",
+        364 => "Turks and Caicos Islands",
+        375..=377 => "Saint Vincent and the Grenadines",
+        378 => "British Virgin Islands",
+        379 => "United States Virgin Islands",
+        401 => "Afghanistan",
+        403 => "Saudi Arabia",
+        405 => "Bangladesh",
+        408 => "Bahrain",
+        410 => "Bhutan",
+        412..=414 => "China",
+        416 => "Taiwan",
+        417 => "Sri Lanka",
+        419 => "India",
+        422 => "Iran",
+        423 => "Azerbaijan",
+        425 => "Iraq",
+        428 => "Israel",
+        431 | 432 => "Japan",
+        434 => "Turkmenistan",
+        436 => "Kazakhstan",
+        437 => "Uzbekistan",
+        438 => "Jordan",
+        440 | 441 => "South Korea",
+        443 => "Palestine",
+        445 => "North Korea",
+        447 => "Kuwait",
+        450 => "Lebanon",
+        451 => "Kyrgyzstan",
+        453 => "Macao",
+        455 => "Maldives",
+        457 => "Mongolia",
+        459 => "Nepal",
+        461 => "Oman",
+        463 => "Pakistan",
+        466 => "Qatar",
+        468 => "Syria",
+        470 | 471 => "United Arab Emirates",
+        472 => "Tajikistan",
+        473 | 475 => "Yemen",
+        477 => "Hong Kong",
+        478 => "Bosnia and Herzegovina",
+        501 => "Adelie Land",
+        503 => "Australia",
+        506 => "Myanmar",
+        508 => "Brunei",
+        510 => "Micronesia",
+        511 => "Palau",
+        512 => "New Zealand",
+        514 | 515 => "Cambodia",
+        516 => "Christmas Island",
+        518 => "Cook Islands",
+        520 => "Fiji",
+        523 => "Cocos (Keeling) Islands",
+        525 => "Indonesia",
+        529 => "Kiribati",
+        531 => "Laos",
+        533 => "Malaysia",
+        536 => "Northern Mariana Islands",
+        538 => "Marshall Islands",
+        540 => "New Caledonia",
+        542 => "Niue",
+        544 => "Nauru",
+        546 => "French Polynesia",
+        548 => "Philippines",
+        550 => "Timor-Leste",
+        553 => "Papua New Guinea",
+        555 => "Pitcairn Islands",
+        557 => "Solomon Islands",
+        559 => "American Samoa",
+        561 => "Samoa",
+        563..=566 => "Singapore",
+        567 => "Thailand",
+        570 => "Tonga",
+        572 => "Tuvalu",
+        574 => "Vietnam",
+        576 | 577 => "Vanuatu",
+        578 => "Wallis and Futuna",
+        601 => "South Africa",
+        603 => "Angola",
+        605 => "Algeria",
+        607 => "Saint Paul and Amsterdam Islands",
+        608 => "Ascension Island",
+        609 => "Burundi",
+        610 => "Benin",
+        611 => "Botswana",
+        612 => "Central African Republic",
+        613 => "Cameroon",
+        615 => "Congo",
+        616 | 620 => "Comoros",
+        617 => "Cabo Verde",
+        618 => "Crozet Archipelago",
+        619 => "Ivory Coast",
+        621 => "Djibouti",
+        622 => "Egypt",
+        624 => "Ethiopia",
+        625 => "Eritrea",
+        626 => "Gabon",
+        627 => "Ghana",
+        629 => "Gambia",
+        630 => "Guinea-Bissau",
+        631 => "Equatorial Guinea",
+        632 => "Guinea",
+        633 => "Burkina Faso",
+        634 => "Kenya",
+        635 => "Kerguelen Islands",
+        636 | 637 => "Liberia",
+        638 => "South Sudan",
+        642 => "Libya",
+        644 => "Lesotho",
+        645 => "Mauritius",
+        647 => "Madagascar",
+        649 => "Mali",
+        650 => "Mozambique",
+        654 => "Mauritania",
+        655 => "Malawi",
+        656 => "Niger",
+        657 => "Nigeria",
+        659 => "Namibia",
+        660 => "Reunion",
+        661 => "Rwanda",
+        662 => "Sudan",
+        663 => "Senegal",
+        664 => "Seychelles",
+        665 => "Saint Helena",
+        666 => "Somalia",
+        667 => "Sierra Leone",
+        668 => "Sao Tome and Principe",
+        669 => "Eswatini",
+        670 => "Chad",
+        671 => "Togo",
+        672 => "Tunisia",
+        674 | 677 => "Tanzania",
+        675 => "Uganda",
+        676 => "Democratic Republic of the Congo",
+        678 => "Zambia",
+        679 => "Zimbabwe",
+        701 => "Argentina",
+        710 => "Brazil",
+        720 => "Bolivia",
+        725 => "Chile",
+        730 => "Colombia",
+        735 => "Ecuador",
+        740 => "Falkland Islands",
+        745 => "French Guiana",
+        750 => "Guyana",
+        755 => "Paraguay",
+        760 => "Peru",
+        765 => "Suriname",
+        770 => "Uruguay",
+        775 => "Venezuela",
+        _ => return None,
+    };
+
+    // Return flag state
+    return Some(flag);
+}
+
+/// Rounds a float to the given number of decimal places and formats it without trailing zeros, e.g. 12.3456789 with precision 3 -> "12.346"
+fn round_to_precision(value: f64, precision: usize) -> String {
+    let factor = 10f64.powi(precision.min(15) as i32);
+    return ((value * factor).round() / factor).to_string();
+}
+
+/// Rounds a float stored as a string, e.g. a latitude, to the given number of decimal places. Values that are not numbers are returned as they are
+fn round_float_string(value: &str, precision: usize) -> String {
+    match value.trim().parse::<f64>() {
+        Ok(v) => return round_to_precision(v, precision),
+        Err(_) => return value.to_string(),
+    }
+}
+
+/// Makes the filename of a vessel file, like make_filename but with the name in the case set in filename_case and ending with .csv.gz if compress_output is set
+fn make_vessel_filename(vessel_name: &str, suffix_number: u64, settings: &Settings) -> String {
+    let filename = make_filename(apply_filename_case(vessel_name, settings).as_str(), suffix_number);
+    if settings.compress_output {
+        return format!("{}.gz", filename);
+    }
+    return filename;
+}
+
+/// Changes the case of a vessel name used in a filename according to filename_case, so a ship's filename does not depend on the case of the name it was received with
+fn apply_filename_case(vessel_name: &str, settings: &Settings) -> String {
+    match settings.filename_case.as_str() {
+        "lower" => return vessel_name.to_lowercase(),
+        "keep" => return vessel_name.to_string(),
+        _ => return vessel_name.to_uppercase(),
+    }
+}
+
+/// Gets the filename of a vessel file without the .csv or .csv.gz extension, e.g. data/imo/NAME_123.csv.gz -> NAME_123
+/// Returns None if the file is not a CSV file
+fn get_vessel_file_stem(file_path: &std::path::Path) -> Option<&str> {
+    let file_name = file_path.file_name()?.to_str()?;
+    return file_name.strip_suffix(".csv.gz").or(file_name.strip_suffix(".csv"));
+}
+
+/// Function that makes valid filenames for vessels.
+/// To make the filenames valid sometimes characters are replaced with an underscore
+/// If the vessel name is blank only the number is used, e.g. 123.csv
+fn make_filename(vessel_name: &str, suffix_number: u64) -> String {
+    // Init filename
+    let mut filename = if vessel_name.trim().is_empty() {
+        format!("{}.csv", suffix_number)
+    } else {
+        format!("{}_{}.csv", vessel_name, suffix_number)
+    };
+    
+    // Replace all invalid characters with underscore
+    for invalid_char in INVALID_FILENAME_CHARACTERS.iter() {
+        filename = filename.replace(*invalid_char, "_");
+    }
+
+    // Return filename
+    return filename;
+}
+
+/// Decodes a rate of turn in AIS format to degrees per minute, positive when turning right
+/// The AIS value is a signed byte where the rate of turn is (value/4.733)^2 with the sign of the value.
+/// Returns None if the value can not be read, is -128 or 128 (not available) or ±127 (turning more than 5° per 30 seconds, exact rate unknown)
+fn decode_rot(raw: &str) -> Option<f64> {
+    let value: i32 = raw.trim().parse().ok()?;
+    if value.abs() >= 127 {
+        return None;
+    }
+    let rate = (value as f64 / 4.733).powi(2);
+    return Some(if value < 0 { -rate } else { rate });
+}
+
+/// Decodes an ETA in AIS format to a human readable UTC string in the format MM-DD HH:MM
+/// The AIS ETA is a bitfield with the month in bits 19-16, day in bits 15-11, hour in bits 10-6 and minute in bits 5-0
+/// AIS ETA has no year. Returns None if the ETA is unknown (zero) or any of the values are out of range
+fn decode_eta(eta: u64) -> Option<String> {
+    // Unknown ETA
+    if eta == 0 {
+        return None;
+    }
+
+    // Unpack bitfield
+    let month = (eta >> 16) & 0xF;
+    let day = (eta >> 11) & 0x1F;
+    let hour = (eta >> 6) & 0x1F;
+    let minute = eta & 0x3F;
+
+    // Check values are in range, AIS uses month 0, day 0, hour 24 and minute 60 to mark unknown values
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    // Return formatted ETA
+    return Some(format!("{:02}-{:02} {:02}:{:02}", month, day, hour, minute));
+}
+
+/// Prints a table with the number of records, time span and average reporting interval of each ship this session
+fn print_session_summary(summary: &std::collections::BTreeMap<String, ShipSummary>) {
+    if summary.is_empty() {
+        println!("No records were collected this session.");
+        return;
+    }
+
+    println!("Session summary:");
+    println!("{:<16} {:<24} {:>8} {:<19} {:<19} {:>12}", "Ship", "Name", "Records", "First", "Last", "Avg interval");
+    for (id, ship) in summary {
+        let first = time::UtcDateTime::from_unix_timestamp(ship.first_timestamp as i64).map(format_datetime).unwrap_or_default();
+        let last = time::UtcDateTime::from_unix_timestamp(ship.last_timestamp as i64).map(format_datetime).unwrap_or_default();
+        let interval = match ship.average_interval_minutes() {
+            Some(minutes) => format!("{:.1} min", minutes),
+            None => "-".to_string(),
+        };
+        println!("{:<16} {:<24} {:>8} {:<19} {:<19} {:>12}", id, ship.name, ship.records, first, last, interval);
+    }
+}
+
+/// Formats a date as YYYY-MM-DD
+fn format_date(datetime: time::UtcDateTime) -> String {
+    return format!("{}-{:02}-{:02}", datetime.year(), datetime.month() as u8, datetime.day());
+}
+
+/// Formats a date and time as YYYY-MM-DD HH:MM:SS
+fn format_datetime(datetime: time::UtcDateTime) -> String {
+    return format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", datetime.year(), datetime.month() as u8, datetime.day(), datetime.hour(), datetime.minute(), datetime.second());
+}
+
+/// Gets a line from a text given its line number (starting at 1)
+/// Used to get the raw text of records that could not be read. Returns an empty string if the line is not found
+fn get_raw_line(text: &str, line_number: Option<u64>) -> String {
+    match line_number {
+        Some(n) if n > 0 => return text.lines().nth((n - 1) as usize).unwrap_or_default().to_string(),
+        _ => return String::new(),
+    }
+}
+
+/// Appends a record that could not be read to the quarantine file, along with a timestamp, where it came from and why it was skipped
+/// Creates the quarantine file with headers if it does not exist
+/// Failing to quarantine a record is not critical so errors are only printed
+fn quarantine_record(source: &str, reason: &str, raw_record: &str) {
+    // Check if the headers need to be written
+    let write_headers = !std::path::Path::new(QUARANTINE_FILE).exists();
+
+    // Make csv writer
+    let file = match fs::OpenOptions::new().create(true).append(true).open(QUARANTINE_FILE) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Error opening {} file, record not quarantined: {}", QUARANTINE_FILE, e);
+            return;
+        }
+    };
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .from_writer(file);
+
+    // Write headers if needed and the record
+    if write_headers {
+        match wtr.write_record(["TIMESTAMP", "SOURCE", "REASON", "RECORD"]) {
+            Ok(_) => {},
+            Err(e) => println!("Error writing headers to {} file: {}", QUARANTINE_FILE, e),
+        }
+    }
+    match wtr.write_record([format_datetime(time::UtcDateTime::now()).as_str(), source, reason, raw_record]) {
+        Ok(_) => {},
+        Err(e) => println!("Error writing record to {} file: {}", QUARANTINE_FILE, e),
+    }
+    match wtr.flush() {
+        Ok(_) => {},
+        Err(e) => println!("Error writing record to {} file: {}", QUARANTINE_FILE, e),
+    }
+}
+
+/// Converts a latitude or longitude value from the API to degrees
+/// AIS format values (data_value_format 0) are in 1/10000 minute, human readable values are in degrees
+/// Returns None if the value is empty, can not be parsed or is outside +-max_degrees (AIS uses 91 and 181 for not available)
+fn coordinate_to_degrees(value: &str, max_degrees: f64, data_value_format: u8) -> Option<f64> {
+    let mut degrees: f64 = value.trim().parse().ok()?;
+    if data_value_format == 0 {
+        degrees /= 600000.0;
+    }
+    if degrees.abs() > max_degrees {
+        return None;
+    }
+    return Some(degrees);
+}
+
+/// Calculates the great-circle distance between two positions in nautical miles using the haversine formula
+/// Positions are given in degrees
+fn haversine_nm(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    // Mean earth radius in nautical miles
+    const EARTH_RADIUS_NM: f64 = 3440.065;
+
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    return 2.0 * EARTH_RADIUS_NM * a.sqrt().asin();
+}
+
+/// Gets the identifier (IMO or MMSI number) from a vessel filename, e.g. NAME_123.csv or NAME_123.csv.gz -> 123
+/// Returns None for files that are not vessel files, including rolled over files like NAME_123.001.csv
+fn get_id_from_filename(file_path: &std::path::Path) -> Option<u64> {
+    let stem = get_vessel_file_stem(file_path)?;
+    let id = match stem.rsplit_once('_') {
+        Some((_, id)) => id,
+        None => stem,
+    };
+    return id.parse().ok();
+}
+
+/// Gets the identifier (IMO or MMSI number) of a vessel file from its filename,
+/// or from its folder for the daily files made when partition_by_date is set, e.g. data/imo/123/2025-01-31.csv -> 123
+/// Returns None for files that are not vessel files, including rolled over files like NAME_123.001.csv
+fn get_id_from_path(file_path: &std::path::Path) -> Option<u64> {
+    match get_id_from_filename(file_path) {
+        Some(id) => return Some(id),
+        None => {}
+    }
+    get_vessel_file_stem(file_path)?;
+    return file_path.parent()?.file_name()?.to_str()?.parse().ok();
+}
+
+/// Gets the paths of all vessel files in a folder, including rolled over files and the daily files in the vessel folders made when partition_by_date is set
+/// Returns an empty vector if the folder does not exist
+fn get_vessel_files(folder: &std::path::Path) -> Result<Vec<std::path::PathBuf>, io::Error> {
+    let mut files: Vec<std::path::PathBuf> = Vec::new();
+    if !folder.exists() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        // Vessel folders are named after the identifier, e.g. data/imo/123/
+        if path.is_dir() {
+            if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.parse::<u64>().is_ok()) {
+                for day_entry in fs::read_dir(&path)? {
+                    let day_path = day_entry?.path();
+                    if get_vessel_file_stem(&day_path).is_some() {
+                        files.push(day_path);
+                    }
+                }
+            }
+        } else if get_vessel_file_stem(&path).is_some() {
+            files.push(path);
+        }
+    }
+    return Ok(files);
+}
+
+/// Merges vessel files in data/imo and data/mmsi that have the same identifier into a single file per vessel,
+/// e.g. OLDNAME_123.csv and NEWNAME_123.csv after a vessel changed its name.
+/// Records are merged in timestamp order and records with duplicate timestamps are dropped, records without a timestamp are all kept since they can not be told apart by it.
+/// The merged file is named after the newest vessel name and uses the columns from the current settings.
+/// The original files are moved to data/backup/{timestamp}/ before the merged file is written.
+/// Rolled over files (NAME_123.001.csv) and the daily files made when partition_by_date is set are left as they are.
+fn compact_data(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let backup_dir = std::path::Path::new("data/backup").join(time::UtcDateTime::now().unix_timestamp().to_string());
+    let headers = make_headers(settings);
+
+    for folder in ["imo", "mmsi"] {
+        let dir = std::path::Path::new("data").join(folder);
+        if !dir.exists() {
+            continue;
+        }
+
+        // Group files by identifier
+        let mut groups: std::collections::BTreeMap<u64, Vec<std::path::PathBuf>> = std::collections::BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match get_id_from_filename(&path) {
+                Some(id) => groups.entry(id).or_default().push(path),
+                None => {}
+            }
+        }
+
+        for (id, files) in groups {
+            // Nothing to merge
+            if files.len() < 2 {
+                continue;
+            }
+            println!("Merging {} files for {} {}", files.len(), folder, id);
+
+            // Read all records from all files, keyed by header so files with different columns can be merged
+            let mut records: Vec<(u64, std::collections::HashMap<String, String>)> = Vec::new();
+            for file in &files {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(b';')
+                    .flexible(true)
+                    .from_reader(open_file_reader(file)?);
+                let file_headers: Vec<String> = reader.headers()?.iter().map(|h| settings.standard_header(h)).collect();
+                for result in reader.records() {
+                    let record = result?;
+                    let values: std::collections::HashMap<String, String> = file_headers.iter().zip(record.iter()).map(|(h, v)| (h.clone(), v.to_string())).collect();
+                    let timestamp: u64 = values.get("TSTAMP").and_then(|t| t.parse().ok()).unwrap_or(0);
+                    records.push((timestamp, values));
+                }
+            }
+
+            // Sort by timestamp and drop duplicates, records without a timestamp are 0 and kept
+            records.sort_by_key(|(timestamp, _)| *timestamp);
+            records.dedup_by(|(timestamp, _), (previous, _)| *timestamp != 0 && timestamp == previous);
+
+            // Name merged file after the newest vessel name
+            let name = records.last().and_then(|(_, values)| values.get("NAME").cloned()).unwrap_or_default();
+            let merged_path = dir.join(make_vessel_filename(name.as_str(), id, settings));
+
+            // Back up the original files
+            let folder_backup_dir = backup_dir.join(folder);
+            fs::create_dir_all(&folder_backup_dir)?;
+            for file in &files {
+                let backup_path = folder_backup_dir.join(file.file_name().ok_or("Invalid file name")?);
+                fs::rename(file, &backup_path)?;
+                move_file_metadata(file, &backup_path)?;
+            }
+
+            // Write merged file
+            let mut wtr = make_csv_writer_builder(settings).from_writer(Vec::new());
+            wtr.write_record(headers.iter().map(|h| settings.header_alias(h)))?;
+            for (_, values) in &records {
+                wtr.write_record(headers.iter().map(|h| values.get(*h).map(|v| v.as_str()).unwrap_or_default()))?;
+            }
+            wtr.flush()?;
+            replace_file_contents(&merged_path, wtr.get_ref())?;
+            println!("Merged {} records into {}", records.len(), merged_path.display());
+        }
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Collects one update for each ship that already has a file in data/imo or data/mmsi, ignoring ships.csv
+/// The identifiers are taken from the filenames and the requests are batched like in a normal cycle
+fn refresh_existing() {
+    // Get settings
+    let settings = match get_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error getting settings from settings.json file: {}", e);
+            return;
+        }
+    };
+    if !settings.get_api_keys().iter().all(|k| is_api_key_set(k)) {
+        println!("Set your AISHub API key in settings.json");
+        return;
+    }
+
+    // Get identifiers from the filenames
+    let imo_nums = get_ids_in_folder("data/imo");
+    let mmsi_nums = get_ids_in_folder("data/mmsi");
+    if imo_nums.is_empty() && mmsi_nums.is_empty() {
+        println!("No vessel files found in data/imo or data/mmsi, nothing to refresh.");
+        return;
+    }
+    println!("Refreshing {} ships by IMO and {} ships by MMSI.", imo_nums.len(), mmsi_nums.len());
+
+    // Collect data
+    let client = match make_http_client(&settings) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error making HTTP client: {}", e);
+            return;
+        }
+    };
+    let batches = make_ship_batches(&imo_nums, &mmsi_nums, settings.batch_size.unwrap_or(BATCH_SIZE_DEFAULT));
+    let api_key = settings.get_api_keys()[0].clone();
+    let data = match get_data_in_batches(&batches, api_key.as_str(), &client, &settings, &mut CycleTimings::default()) {
+        Ok((d, None)) => d,
+        Ok((d, Some(e))) => {
+            println!("Error getting data from AISHub API: {}\nSaving the {} records from the batches before the error.", e, d.len());
+            d
+        },
+        Err(e) => {
+            println!("Error getting data from AISHub API: {}", e);
+            return;
+        }
+    };
+
+    // Store data
+    let data = filter_future_timestamps(data, &settings);
+    let data = filter_time_window(data, &settings);
+    let data = filter_min_sog(data, &settings);
+    let mut data = filter_polygon(data, &settings);
+    reconcile_imo_numbers(&mut data, &mut std::collections::HashMap::new(), &settings);
+    match save_data_with_policy(&data, &std::collections::HashMap::new(), &settings) {
+        Ok((_, stats)) => println!("{} vessels returned: {}.", data.len(), stats),
+        Err(e) => println!("Error saving data: {}", e),
+    }
+}
+
+/// Collects data for the ships in ships.csv with the age limit (the interval parameter of the API) going from max_age down by step minutes at a time, then exits.
+/// AISHub only returns the latest position of each ship, so this picks up ships whose latest position is older than age_max, not older positions of ships that have reported since.
+/// Waits update_interval minutes between requests and tries the same age again after too frequent requests. Records are stored with the normal duplicate checks
+fn backfill(max_age: u64, step: u64) {
+    // Get settings
+    let mut settings = match get_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error getting settings from settings.json file: {}", e);
+            return;
+        }
+    };
+    enforce_min_update_interval(&mut settings);
+    if !settings.get_api_keys().iter().all(|k| is_api_key_set(k)) {
+        println!("Set your AISHub API key in settings.json");
+        return;
+    }
+
+    // Stop between requests when ctrl+C is pressed
+    match ctrlc::set_handler(|| SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst)) {
+        Ok(_) => {},
+        Err(e) => println!("Error setting ctrl+C handler, the program will stop immediately when ctrl+C is pressed: {}", e),
+    };
+
+    // Make requests for the ships in ships.csv
+    let (imo_nums, mmsi_nums, labels) = get_list_of_ships(&settings);
+    let batches = make_ship_batches(&imo_nums, &mmsi_nums, settings.batch_size.unwrap_or(BATCH_SIZE_DEFAULT));
+    let client = match make_http_client(&settings) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error making HTTP client: {}", e);
+            return;
+        }
+    };
+    let api_key = settings.get_api_keys()[0].clone();
+
+    let mut age = max_age;
+    let mut saved: usize = 0;
+    while age > 0 {
+        // Request with this age limit
+        let mut request_settings = settings.clone();
+        request_settings.age_max = Some(Minutes::Number(age));
+        let (data, error) = match get_data_in_batches(&batches, api_key.as_str(), &client, &request_settings, &mut CycleTimings::default()) {
+            Ok(result) => result,
+            Err(e) => (Vec::new(), Some(e)),
+        };
+
+        // Save the records, including the ones from the batches before an error
+        if !data.is_empty() || error.is_none() {
+            let data = filter_future_timestamps(data, &settings);
+            let data = filter_time_window(data, &settings);
+            let data = filter_min_sog(data, &settings);
+            let mut data = filter_polygon(data, &settings);
+            reconcile_imo_numbers(&mut data, &mut std::collections::HashMap::new(), &settings);
+            match save_data_with_policy(&data, &labels, &settings) {
+                Ok((_, stats)) => {
+                    println!("Positions up to {} minutes old: {} vessels returned: {}.", age, data.len(), stats);
+                    saved += stats.written;
+                },
+                Err(e) => println!("Error saving positions up to {} minutes old: {}", age, e),
+            }
+        }
+
+        match error {
+            None => age = age.saturating_sub(step),
+            Some(CollectError::Interrupted) => break,
+            // Try the same age again after waiting, the update interval may have been increased
+            Some(CollectError::RateLimited(_)) => {
+                match get_settings() {
+                    Ok(s) => settings = s,
+                    Err(e) => println!("Warning: Error getting settings from settings file: {}\nUsing previous settings.", e),
+                }
+                enforce_min_update_interval(&mut settings);
+                println!("Too frequent requests made to AISHub API, trying positions up to {} minutes old again after {} minute/s.", age, settings.update_interval);
+            },
+            Some(e) => {
+                println!("Error getting positions up to {} minutes old from AISHub API, skipping them: {}", age, e);
+                age = age.saturating_sub(step);
+            }
+        }
+
+        // Wait before the next request so AISHub's rate limit is respected
+        if age > 0 && !sleep_unless_shutdown(std::time::Duration::from_secs(settings.update_interval as u64 * 60)) {
+            break;
+        }
+    }
+
+    println!("Backfill done, {} new records saved.", saved);
+}
+
+/// Makes a single small request to check the API key and the connection to AISHub, then prints whether it worked, the number of records and the round trip time.
+/// Asks for the first ship in ships.csv, or a tiny bounding box if there is no ships.csv. Nothing is saved and the settings file is never changed, not even after too frequent requests
+fn check_api() {
+    // Get settings
+    let settings = match get_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error getting settings from settings file: {}", e);
+            return;
+        }
+    };
+    let api_key = settings.get_api_keys()[0].clone();
+    if !is_api_key_set(api_key.as_str()) {
+        println!("FAILED: No API key set. Set api_key in {} or the {} environment variable.", get_settings_path(), API_KEY_ENV_VAR);
+        return;
+    }
+    let client = match make_http_client(&settings) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error making HTTP client: {}", e);
+            return;
+        }
+    };
+
+    // Ask for one ship, or a tiny bounding box
+    let ship = if std::path::Path::new("ships.csv").exists() {
+        let (imo_nums, mmsi_nums, _) = get_list_of_ships(&settings);
+        imo_nums.first().map(|imo| (Some(imo.clone()), None)).or(mmsi_nums.first().map(|mmsi| (None, Some(mmsi.clone()))))
+    } else {
+        None
+    };
+    let base_url = settings.base_url.as_deref().unwrap_or(AISHUB_BASE_URL_DEFAULT);
+    let url = match &ship {
+        Some((imo, mmsi)) => make_aishub_url(base_url, api_key.as_str(), settings.data_value_format, settings.output_format.as_str(), settings.compression, None, None, None, None, mmsi.as_deref(), imo.as_deref(), None),
+        None => make_aishub_url(base_url, api_key.as_str(), settings.data_value_format, settings.output_format.as_str(), settings.compression, Some(0.0), Some(0.01), Some(0.0), Some(0.01), None, None, None),
+    };
+    let url = match url {
+        Ok(u) => u,
+        Err(e) => {
+            println!("Error making AISHub API URL from {}: {}", base_url, e);
+            return;
+        }
+    };
+    println!("Checking AISHub API at {}", redact_api_key(url.as_str()));
+
+    // Make request
+    let start = std::time::Instant::now();
+    let result = check_api_request(url, &client, &settings);
+    let round_trip = start.elapsed();
+
+    // Report result
+    match result {
+        Ok(data) => println!("OK: The API key was accepted. {} records returned in {:.2?}.", data.len(), round_trip),
+        Err(CollectError::Empty) => println!("OK: The API key was accepted but the response was empty, in {:.2?}.", round_trip),
+        Err(CollectError::RateLimited(body)) => println!("RATE LIMITED: The API key was accepted but too frequent requests have been made, in {:.2?}. Wait for the update interval and check that no other program is using the same key.\nResponse: {}", round_trip, body.trim()),
+        Err(CollectError::HttpStatus(status, snippet)) if status == 401 || status == 403 => println!("AUTHENTICATION FAILED: AISHub returned HTTP status {}, check the API key.\nResponse: {}", status, snippet),
+        Err(CollectError::Parse(message)) if message.contains("AISHub API returned an error") => println!("AUTHENTICATION FAILED: AISHub rejected the request, most likely because of the API key.\n{}", message),
+        Err(e @ CollectError::Network { .. }) => println!("NETWORK FAILURE: Could not reach AISHub after {:.2?}: {}", round_trip, e),
+        Err(e) => println!("FAILED: {}", e),
+    }
+}
+
+/// Writes the records in the vessel files in a folder to stdout as CSV in timestamp order, waiting between records for the time between their timestamps divided by speed.
+/// Includes the files in the imo and mmsi folders inside it, so it can be given the data folder. Only the standard columns are written.
+/// No requests are made and nothing is saved. Log messages go to stderr so stdout only has data
+fn simulate(dir: &str, speed: f64) {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+    let settings = get_settings().unwrap_or_default();
+
+    // Get vessel files
+    let folder = std::path::Path::new(dir);
+    let mut files: Vec<std::path::PathBuf> = Vec::new();
+    for path in [folder.to_path_buf(), folder.join("imo"), folder.join("mmsi")] {
+        match get_vessel_files(&path) {
+            Ok(mut f) => files.append(&mut f),
+            Err(e) => println!("Error reading {}: {}", path.display(), e),
+        }
+    }
+    if files.is_empty() {
+        println!("No vessel files found in {}, nothing to simulate.", dir);
+        return;
+    }
+
+    // Read the standard columns of every record along with its timestamp
+    let mut records: Vec<(u64, Vec<String>)> = Vec::new();
+    for path in &files {
+        let mut reader = match open_file_reader(path) {
+            Ok(r) => csv::ReaderBuilder::new().delimiter(b';').flexible(true).from_reader(r),
+            Err(e) => {
+                println!("Error reading {}, skipping it: {}", path.display(), e);
+                continue;
+            }
+        };
+        let headers: Vec<String> = match reader.headers() {
+            Ok(h) => h.iter().map(|h| settings.standard_header(h)).collect(),
+            Err(e) => {
+                println!("Error reading headers of {}, skipping it: {}", path.display(), e);
+                continue;
+            }
+        };
+        let indexes: Vec<Option<usize>> = STANDARD_COLUMNS.iter().map(|c| headers.iter().position(|h| h == c)).collect();
+        let timestamp_index = headers.iter().position(|h| h == "TSTAMP");
+        for result in reader.records() {
+            let record = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Error reading record from {}, skipping it: {}", path.display(), e);
+                    continue;
+                }
+            };
+            match timestamp_index.and_then(|i| record.get(i)).and_then(|t| t.parse::<u64>().ok()) {
+                Some(timestamp) => records.push((timestamp, indexes.iter().map(|i| i.and_then(|i| record.get(i)).unwrap_or_default().to_string()).collect())),
+                None => {}
+            }
         }
-        match header_order[20] {
-            Some(index) => vessel_info.timestamp = record[index].parse().unwrap(),
-            None => {}
+    }
+    records.sort_by_key(|(timestamp, _)| *timestamp);
+    println!("Simulating {} records from {} files at {}x speed.", records.len(), files.len(), speed);
+
+    // Write records, waiting for the time between them
+    let mut wtr = make_csv_writer_builder(&settings).from_writer(io::stdout());
+    let mut result = wtr.write_record(STANDARD_COLUMNS.iter().map(|h| settings.header_alias(h))).and_then(|_| wtr.flush().map_err(csv::Error::from));
+    let mut previous_timestamp: Option<u64> = None;
+    for (timestamp, record) in records {
+        if result.is_err() {
+            break;
         }
-        match header_order[21] {
-            Some(index) => vessel_info.vessel_type = record[index].parse().unwrap(),
+        match previous_timestamp {
+            Some(previous) => {
+                let wait = match get_simulate_wait(timestamp - previous, speed) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        println!("{}\nUsage: --simulate <dir> [speed]", e);
+                        return;
+                    }
+                };
+                if !sleep_unless_shutdown(wait) {
+                    break;
+                }
+            },
             None => {}
         }
-
-        // Append to data vector
-        data.push(vessel_info);
+        previous_timestamp = Some(timestamp);
+        result = wtr.write_record(&record).and_then(|_| wtr.flush().map_err(csv::Error::from));
     }
 
-    // Return the data vector
-    return Ok(data);
+    // A closed pipe, e.g. the reading program stopped, ends the simulation
+    match result {
+        Ok(_) => println!("Simulation done."),
+        Err(e) => println!("Error writing to stdout, stopping simulation: {}", e),
+    }
 }
 
-/// Gets the order of headers in the CSV response
-/// Returns a vector where the first value is the index of the first value in the VesselInfo struct, second value is the index of the second value, etc.
-/// Based on the VesselInfo struct definition (alphabetical order) and https://www.aishub.net/api
-fn get_header_order(headers: &csv::StringRecord) -> Vec<Option<usize>> {
-    // Init vector to hold order
-    let mut order: Vec<Option<usize>> = vec![None; 22];
-
-    // Loop through headers and get index of each value
-    for (i, header) in headers.iter().enumerate() {
-        match header {
-            "A" =>              order[0] = Some(i),
-            "B" =>              order[1] = Some(i),
-            "C" =>              order[2] = Some(i),
-            "CALLSIGN" =>       order[3] = Some(i),
-            "COG" =>            order[4] = Some(i),
-            "D" =>              order[5] = Some(i),
-            "DEST" =>           order[6] = Some(i),
-            "DEVICE" =>         order[7] = Some(i),
-            "DRAUGHT" =>        order[8] = Some(i),
-            "ETA" =>            order[9] = Some(i),
-            "HEADING" =>        order[10] = Some(i),
-            "IMO" =>            order[11] = Some(i),
-            "LATITUDE" =>       order[12] = Some(i),
-            "LONGITUDE" =>      order[13] = Some(i),
-            "MMSI" =>           order[14] = Some(i),
-            "NAME" =>           order[15] = Some(i),
-            "NAVSTAT" =>        order[16] = Some(i),
-            "PAC" =>            order[17] = Some(i),
-            "ROT" =>            order[18] = Some(i),
-            "SOG" =>            order[19] = Some(i),
-            "TSTAMP" =>         order[20] = Some(i),    // Timestamp header is "TSTAMP"
-            "TYPE" =>           order[21] = Some(i),    // Vessel type header is "TYPE"
-            _ => {println!("Ignoring unknown header in CSV response: {}.\nIf this header is needed, please submit an issue to the aishub_data_collector github repository:\nhttps://github.com/G0rocks/aishub_data_collector/issues.", header);}
-        }
-    }
-
-    // Return order vector
-    return order;
-}
-
-/// Function that saves the data to the database
-/// If the files don't exist, creates them
-/// If the files already exist, appends to them
-/// Note: Prioritizes IMO number over MMSI number, so if both exist, saves to IMO file only
-fn save_data(data: &Vec<VesselInfo>) -> Result<(), Box<dyn std::error::Error>> {
-    // Check if data folder exists, if not, create it
-    if !std::path::Path::new("data").exists() {
-        fs::create_dir("data")?;
+/// Works out how long --simulate waits between two records, the time between their timestamps divided by speed
+/// Returns an error if the wait is too long to be represented, e.g. for a tiny speed like 1e-300
+fn get_simulate_wait(gap_secs: u64, speed: f64) -> Result<std::time::Duration, String> {
+    match std::time::Duration::try_from_secs_f64(gap_secs as f64 / speed) {
+        Ok(wait) => return Ok(wait),
+        Err(_) => return Err(format!("Speed {} is too small, waiting {} seconds between records at that speed is too long. Use a larger speed", speed, gap_secs)),
     }
+}
 
-    // Move to data folder
-    std::env::set_current_dir("data")?;
+/// Writes the track of a ship to a GPX file, output_path or {identifier}.gpx if not given, with a track point for each record in its vessel files in data/imo and data/mmsi.
+/// Track points are in timestamp order. Records without a position are skipped and records without a timestamp have no time
+fn export_gpx(identifier: &str, output_path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = get_settings().unwrap_or_default();
+    let id: u64 = match identifier.trim().parse() {
+        Ok(id) => id,
+        Err(_) => return Err(Box::from(format!("Invalid identifier: {}. Use the IMO or MMSI number of the ship", identifier))),
+    };
 
-    // Check if imo folder exists, if not create it
-    if !std::path::Path::new("imo").exists() {
-        fs::create_dir("imo")?;
+    // Read the position, timestamp and name of every record of the ship
+    let mut points: Vec<(u64, f64, f64)> = Vec::new();
+    let mut name: Option<(u64, String)> = None;
+    for folder in ["imo", "mmsi"] {
+        for path in get_vessel_files(&std::path::Path::new("data").join(folder))? {
+            if get_id_from_path(&path) != Some(id) {
+                continue;
+            }
+            let mut reader = csv::ReaderBuilder::new().delimiter(b';').flexible(true).from_reader(open_file_reader(&path)?);
+            let headers: Vec<String> = reader.headers()?.iter().map(|h| settings.standard_header(h)).collect();
+            let index = |column: &str| headers.iter().position(|h| h == column);
+            let (lat_index, lon_index, timestamp_index, name_index) = (index("LATITUDE"), index("LONGITUDE"), index("TSTAMP"), index("NAME"));
+            for result in reader.records() {
+                let record = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!("Error reading record from {}, skipping it: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let timestamp: u64 = timestamp_index.and_then(|i| record.get(i)).and_then(|t| t.parse().ok()).unwrap_or(0);
+                let lat = lat_index.and_then(|i| record.get(i)).and_then(|v| coordinate_to_degrees(v, 90.0, settings.data_value_format));
+                let lon = lon_index.and_then(|i| record.get(i)).and_then(|v| coordinate_to_degrees(v, 180.0, settings.data_value_format));
+                match (lat, lon) {
+                    (Some(lat), Some(lon)) => points.push((timestamp, lat, lon)),
+                    _ => continue,
+                }
+
+                // Use the newest name as the track name
+                match name_index.and_then(|i| record.get(i)) {
+                    Some(n) if !n.trim().is_empty() && name.as_ref().is_none_or(|(t, _)| timestamp >= *t) => name = Some((timestamp, n.trim().to_string())),
+                    _ => {}
+                }
+            }
+        }
+    }
+    if points.is_empty() {
+        return Err(Box::from(format!("No positions found for {} in data/imo or data/mmsi", id)));
     }
+    points.sort_by_key(|(timestamp, _, _)| *timestamp);
 
-    // Check if mmsi folder exists, if not create it
-    if !std::path::Path::new("mmsi").exists() {
-        fs::create_dir("mmsi")?;
+    // Make GPX
+    let track_name = name.map(|(_, n)| n).unwrap_or(id.to_string());
+    let mut gpx = String::new();
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str(&format!("<gpx version=\"1.1\" creator=\"AISHub-data-collector {}\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n", env!("CARGO_PKG_VERSION")));
+    gpx.push_str(&format!("  <trk>\n    <name>{}</name>\n    <trkseg>\n", quick_xml::escape::escape(track_name.as_str())));
+    for (timestamp, lat, lon) in &points {
+        if *timestamp > 0 {
+            let datetime = time::UtcDateTime::from_unix_timestamp(*timestamp as i64)?;
+            gpx.push_str(&format!("      <trkpt lat=\"{}\" lon=\"{}\"><time>{}</time></trkpt>\n", lat, lon, format_datetime(datetime).replace(' ', "T") + "Z"));
+        } else {
+            gpx.push_str(&format!("      <trkpt lat=\"{}\" lon=\"{}\"></trkpt>\n", lat, lon));
+        }
     }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
 
-    // Loop through data vector for each vessel
-    for vessel in data {
-        // if IMO number exists, enter imo folder
-        if vessel.imo != 0 {
-            // Enter folder
-            std::env::set_current_dir("imo")?;
-            // Create filename
-            let filename = make_filename(vessel.name.as_str(), vessel.imo);
-
-            // Check if file exists, if not create it with headers
-            if !std::path::Path::new(&filename).exists() {
-                // Create file with headers
-                make_empty_csv_file(filename.as_str())?;
-            }
-         
-            // Make csv file reader
-            let reader = csv::Reader::from_path(filename.as_str())?;
-
-            // Get latest timestamp in last line of file
-            let latest_timestamp: u64 = match reader.into_records().last() {
-                Some(Ok(record)) => record.get(20).unwrap().parse()?,
-                Some(Err(e)) => {
-                    return Err(Box::from(format!("Error reading record from CSV file: {}", e)));
-                }
-                None => 0, // If file is empty, set latest timestamp to 0
-            };
+    // Write GPX file
+    let output_path = output_path.map(|p| p.to_string()).unwrap_or(format!("{}.gpx", id));
+    fs::write(&output_path, gpx)?;
+    println!("Exported {} positions of {} to {}", points.len(), track_name, output_path);
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Makes the request for check_api, like get_data_from_aishub_api but without saving raw responses or increasing the update interval after too frequent requests
+fn check_api_request(url: String, client: &reqwest::blocking::Client, settings: &Settings) -> Result<Vec<VesselInfo>, CollectError> {
+    // Get response
+    let response = match client.get(url).send() {
+        Ok(r) => r,
+        Err(e) => return Err(CollectError::from_request_error("Error making request to AISHub API", &e)),
+    };
+    let status = response.status();
+    let body = match response.bytes() {
+        Ok(bytes) => decode_response(&bytes),
+        Err(e) => return Err(CollectError::from_request_error("Error reading response text", &e)),
+    };
+
+    // Check for errors
+    if body.starts_with("Too frequent requests") {
+        return Err(CollectError::RateLimited(body));
+    }
+    if !status.is_success() {
+        let snippet: String = body.chars().take(ERROR_BODY_SNIPPET_LENGTH).collect();
+        return Err(CollectError::HttpStatus(status.as_u16(), snippet.trim().to_string()));
+    }
+    if body.trim().is_empty() {
+        return Err(CollectError::Empty);
+    }
+
+    // Parse the response
+    match parse_response(&body, settings.output_format.as_str(), settings.data_value_format) {
+        Ok(data) => return Ok(data),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(CollectError::Truncated(e.to_string())),
+        Err(e) => return Err(CollectError::Parse(e.to_string())),
+    }
+}
 
-            // Check latest entry timestamp in file to avoid duplicates
-            if vessel.timestamp <= latest_timestamp {
-                // Exit back to data folder
-                std::env::set_current_dir("..")?;
-                continue; // Skip to next vessel
+/// Gets the identifiers of the vessel files in a folder, sorted and without duplicates since rolled over files and daily files have the same identifier
+/// Returns an empty vector if the folder does not exist
+fn get_ids_in_folder(folder: &str) -> Vec<String> {
+    let mut ids: std::collections::BTreeSet<u64> = std::collections::BTreeSet::new();
+    match get_vessel_files(std::path::Path::new(folder)) {
+        Ok(files) => {
+            for file in files {
+                match get_id_from_path(&file) {
+                    Some(id) => { ids.insert(id); },
+                    None => {}
+                }
             }
+        },
+        Err(_) => {} // No folder, no ships
+    }
+    return ids.iter().map(|id| id.to_string()).collect();
+}
 
-            // Make file csv writer
-            let mut wtr = csv::WriterBuilder::new()
-                .delimiter(b';')
-                .from_writer(fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(filename.as_str())?);
+/// Prints a summary of the vessel files in data/imo and data/mmsi, including rolled over files:
+/// number of files, records and bytes, the earliest and latest timestamps and the ships with the most records
+/// Only reads the files, never changes them
+fn print_data_stats(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file_count: usize = 0;
+    let mut record_count: u64 = 0;
+    let mut total_bytes: u64 = 0;
+    let mut earliest: Option<u64> = None;
+    let mut latest: Option<u64> = None;
+    // Number of records per ship, keyed by folder and identifier, e.g. "IMO 1234567"
+    let mut ship_records: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
 
-            // Append data to file
-            match write_data_to_file(&mut wtr, &vessel) {
-                Ok(_) => {},
-                Err(e) => {
-                    return Err(Box::from(format!("Error writing data to CSV file: {}", e)));
+    for folder in ["imo", "mmsi"] {
+        for path in get_vessel_files(&std::path::Path::new("data").join(folder))? {
+            file_count += 1;
+            total_bytes += fs::metadata(&path)?.len();
+
+            // Count records and find the timestamp range
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(b';')
+                .flexible(true)
+                .from_reader(open_file_reader(&path)?);
+            let timestamp_index = reader.headers()?.iter().position(|h| settings.standard_header(h) == "TSTAMP");
+            let mut file_records: u64 = 0;
+            for result in reader.records() {
+                let record = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!("Error reading record from {}, skipping it: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                file_records += 1;
+                match timestamp_index.and_then(|i| record.get(i)).and_then(|t| t.parse::<u64>().ok()) {
+                    Some(timestamp) => {
+                        earliest = Some(earliest.map_or(timestamp, |e| e.min(timestamp)));
+                        latest = Some(latest.map_or(timestamp, |l| l.max(timestamp)));
+                    },
+                    None => {}
                 }
+            }
+            record_count += file_records;
+
+            // Add to the ship's total, rolled over files and daily files count towards the same ship
+            let id = match get_id_from_path(&path) {
+                Some(id) => id.to_string(),
+                None => path.display().to_string(),
             };
+            *ship_records.entry(format!("{} {}", folder.to_uppercase(), id)).or_default() += file_records;
+        }
+    }
+
+    // Print summary
+    let format_timestamp = |timestamp: Option<u64>| timestamp.and_then(|t| time::UtcDateTime::from_unix_timestamp(t as i64).ok()).map(format_datetime).unwrap_or("-".to_string());
+    println!("Files:    {}", file_count);
+    println!("Ships:    {}", ship_records.len());
+    println!("Records:  {}", record_count);
+    println!("Size:     {:.1} MB", total_bytes as f64 / 1024.0 / 1024.0);
+    println!("Earliest: {}", format_timestamp(earliest));
+    println!("Latest:   {}", format_timestamp(latest));
 
-            // Exit back to data folder
-            std::env::set_current_dir("..")?;
+    // Print the ships with the most records
+    let mut busiest: Vec<(&String, &u64)> = ship_records.iter().collect();
+    busiest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    if !busiest.is_empty() {
+        println!("Busiest ships:");
+        println!("{:<16} {:>10}", "Ship", "Records");
+        for (ship, records) in busiest.iter().take(STATS_BUSIEST_SHIPS) {
+            println!("{:<16} {:>10}", ship, records);
         }
-        // if MMSI number exists, enter mmsi folder
-        else if vessel.mmsi != 0 {
-            // Enter folder
-            std::env::set_current_dir("mmsi")?;
-            // Create filename
-            let filename = make_filename(vessel.name.as_str(), vessel.mmsi);
-
-            // Check if file exists, if not create it with headers
-            if !std::path::Path::new(&filename).exists() {
-                // Create file with headers
-                make_empty_csv_file(filename.as_str())?;
-            }
-         
-            // Make csv file reader
-            let reader = csv::Reader::from_path(filename.as_str())?;
-
-            // Get latest timestamp in last line of file
-            let latest_timestamp: u64 = match reader.into_records().last() {
-                Some(Ok(record)) => record.get(20).unwrap().parse()?,
-                Some(Err(e)) => {
-                    return Err(Box::from(format!("Error reading record from CSV file: {}", e)));
-                }
-                None => 0, // If file is empty, set latest timestamp to 0
-            };
+    }
 
-            // Check latest entry timestamp in file to avoid duplicates
-            if vessel.timestamp <= latest_timestamp {
-                // Exit back to data folder
-                std::env::set_current_dir("..")?;
-                continue; // Skip to next vessel
-            }
+    // Return Ok
+    return Ok(());
+}
+
+/// Prints the ships in ships.csv along with the ships that have vessel files in data/imo and data/mmsi,
+/// with the number of files, the newest vessel name and timestamp of each. Only reads the files, never changes them
+fn list_ships(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    // Get the configured ships, keyed like the ships in the data folder, e.g. "IMO 1234567"
+    let mut configured: Vec<String> = Vec::new();
+    if std::path::Path::new("ships.csv").exists() {
+        let (imo, mmsi, _) = get_list_of_ships(settings);
+        configured.extend(imo.iter().map(|n| format!("IMO {}", n)));
+        configured.extend(mmsi.iter().map(|n| format!("MMSI {}", n)));
+    } else {
+        println!("No ships.csv file found, only listing the ships in the data folder.");
+    }
 
-            // Make file csv writer
-            let mut wtr = csv::Writer::from_writer(fs::OpenOptions::new().append(true).open(filename.as_str())?);
+    // Get the ships in the data folder
+    let mut ships: std::collections::HashMap<String, ListedShip> = std::collections::HashMap::new();
+    for folder in ["imo", "mmsi"] {
+        for path in get_vessel_files(&std::path::Path::new("data").join(folder))? {
+            let id = match get_id_from_path(&path) {
+                Some(id) => id,
+                None => continue,
+            };
+            let ship = ships.entry(format!("{} {}", folder.to_uppercase(), id)).or_default();
+            ship.files += 1;
 
-            // Append data to file
-            match write_data_to_file(&mut wtr, &vessel) {
-                Ok(_) => {},
+            // Rolled over and daily files can be older or newer, so keep the newest record of all of them
+            let last_record = match get_last_record(&path, settings) {
+                Ok(r) => r,
                 Err(e) => {
-                    return Err(Box::from(format!("Error writing data to CSV file: {}", e)));
+                    println!("Error reading {}, skipping it: {}", path.display(), e);
+                    continue;
                 }
             };
-
-            // Exit back to data folder
-            std::env::set_current_dir("..")?;
+            match last_record.get("TSTAMP").and_then(|t| t.parse::<u64>().ok()) {
+                Some(timestamp) if ship.newest.is_none_or(|newest| timestamp > newest) => {
+                    ship.newest = Some(timestamp);
+                    ship.name = last_record.get("NAME").unwrap_or_default().trim().to_string();
+                    ship.imo = last_record.get("IMO").and_then(|n| n.parse().ok()).unwrap_or(0);
+                    ship.mmsi = last_record.get("MMSI").and_then(|n| n.parse().ok()).unwrap_or(0);
+                },
+                _ => {}
+            }
         }
     }
 
-    // Exit data folder
-    std::env::set_current_dir("..")?;
+    // Match the configured ships to their files, a ship listed by MMSI is saved in data/imo if it has an IMO number and the other way around
+    let find_ship = |key: &String| -> Option<&String> {
+        match ships.get_key_value(key) {
+            Some((k, _)) => return Some(k),
+            None => {}
+        }
+        let (kind, number) = key.split_once(' ')?;
+        let number: u64 = number.parse().ok()?;
+        return ships.iter().find(|(_, ship)| if kind == "IMO" { ship.imo == number } else { ship.mmsi == number }).map(|(k, _)| k);
+    };
+    let matched: Vec<(&String, Option<&String>)> = configured.iter().map(|key| (key, find_ship(key))).collect();
+    let mut unconfigured: Vec<&String> = ships.keys().filter(|k| !matched.iter().any(|(_, m)| *m == Some(*k))).collect();
+    unconfigured.sort();
+
+    // Print configured ships first in the order of ships.csv, then the ships that only have data files
+    let format_timestamp = |timestamp: Option<u64>| timestamp.and_then(|t| time::UtcDateTime::from_unix_timestamp(t as i64).ok()).map(format_datetime).unwrap_or("-".to_string());
+    println!("{:<16} {:<16} {:<24} {:>5} {}", "ships.csv", "Data folder", "Name", "Files", "Last record");
+    for (key, data_key) in matched.iter().copied().chain(unconfigured.iter().map(|k| (*k, Some(*k)))) {
+        let listed = if configured.contains(key) { key.as_str() } else { "-" };
+        match data_key.and_then(|k| ships.get(k).map(|ship| (k, ship))) {
+            Some((k, ship)) => println!("{:<16} {:<16} {:<24} {:>5} {}", listed, k, ship.name, ship.files, format_timestamp(ship.newest)),
+            None => println!("{:<16} {:<16} {:<24} {:>5} {}", listed, "-", "-", 0, "-"),
+        }
+    }
+    println!("{} ships in ships.csv, {} of them without data files. {} ships with data files not in ships.csv.", configured.len(), matched.iter().filter(|(_, m)| m.is_none()).count(), unconfigured.len());
 
     // Return Ok
     return Ok(());
 }
 
-/// Makes a new empty .csv file with the correct headers in the correct order
-fn make_empty_csv_file(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Sanity check the file_path ends with ".csv"
-    if !file_path.ends_with(".csv") {
-        return Err(Box::from("File path must end with .csv"));
+/// Prunes records older than retention_days from all vessel files in data/imo and data/mmsi, including rolled over files
+/// If retention_delete_empty is true, files with no records left are deleted
+fn prune_old_records(retention_days: u64, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let cutoff = (time::UtcDateTime::now().unix_timestamp() as u64).saturating_sub(retention_days * 24 * 60 * 60);
+    let mut pruned: usize = 0;
+
+    for folder in ["data/imo", "data/mmsi"] {
+        for path in get_vessel_files(std::path::Path::new(folder))? {
+            pruned += prune_file(&path, cutoff, settings)?;
+        }
     }
 
-    // Create CSV writer
-    let mut wtr = csv::WriterBuilder::new()
+    if pruned > 0 {
+        println!("Pruned {} records older than {} days", pruned, retention_days);
+    }
+
+    // Return Ok
+    return Ok(());
+}
+
+/// Removes records with a timestamp older than cutoff from a vessel file by rewriting the file without them
+/// If retention_delete_empty is true and no records are left, the file is deleted
+/// Returns the number of records removed
+fn prune_file(file_path: &std::path::Path, cutoff: u64, settings: &Settings) -> Result<usize, Box<dyn std::error::Error>> {
+    // Read headers and records
+    let mut reader = csv::ReaderBuilder::new()
         .delimiter(b';')
-        .from_path(file_path)?;
+        .flexible(true)
+        .from_reader(open_file_reader(file_path)?);
+    let headers = reader.headers()?.clone();
+    let timestamp_index = headers.iter().position(|h| settings.standard_header(h) == "TSTAMP").ok_or("Missing TSTAMP column")?;
+    let records: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>()?;
+
+    // Keep records newer than the cutoff, records with unreadable timestamps are kept
+    let kept: Vec<&csv::StringRecord> = records.iter()
+        .filter(|r| r.get(timestamp_index).and_then(|t| t.parse::<u64>().ok()).is_none_or(|t| t >= cutoff))
+        .collect();
+    let removed = records.len() - kept.len();
+
+    // Nothing to do
+    if removed == 0 {
+        return Ok(0);
+    }
+
+    // Delete file if empty
+    if kept.is_empty() && settings.retention_delete_empty {
+        fs::remove_file(file_path)?;
+        let metadata_path = make_metadata_path(file_path);
+        if metadata_path.exists() {
+            fs::remove_file(metadata_path)?;
+        }
+        return Ok(removed);
+    }
 
-    // Write headers
-    wtr.write_record(&["A", "B", "C", "CALLSIGN", "COG", "D", "DEST", "DRAUGHT", "DEVICE", "ETA", "HEADING", "IMO", "LATITUDE", "LONGITUDE", "MMSI", "NAME", "NAVSTAT", "PAC", "ROT", "SOG", "TSTAMP", "TYPE"])?;
+    // Replace the file with the kept records
+    let mut wtr = make_csv_writer_builder(settings)
+        .flexible(true)
+        .from_writer(Vec::new());
+    wtr.write_record(&headers)?;
+    for record in kept {
+        wtr.write_record(record)?;
+    }
     wtr.flush()?;
+    replace_file_contents(file_path, wtr.get_ref())?;
 
-    // Return Ok
-    return Ok(());
+    // Return number of removed records
+    return Ok(removed);
 }
 
-/// Writes data to file given a csv writer
-fn write_data_to_file(wtr: &mut csv::Writer<std::fs::File>, vessel: &VesselInfo) -> Result<(), Box<dyn std::error::Error>> {
-    // Write record
-    wtr.write_record(&[
-        vessel.a.to_string(),
-        vessel.b.to_string(),
-        vessel.c.to_string(),
-        vessel.callsign.clone(),
-        vessel.cog.to_string(),
-        vessel.d.to_string(),
-        vessel.dest.clone(),
-        vessel.draught.to_string(),
-        vessel.device.clone(),
-        vessel.eta.to_string(),
-        vessel.heading.to_string(),
-        vessel.imo.to_string(),
-        vessel.latitude.clone(),
-        vessel.longitude.clone(),
-        vessel.mmsi.to_string(),
-        vessel.name.clone(),
-        vessel.navstat.clone(),
-        vessel.pac.to_string(),
-        vessel.rot.clone(),
-        vessel.sog.to_string(),
-        vessel.timestamp.to_string(),
-        vessel.vessel_type.to_string()
-    ])?;
+/// Removes the oldest records from a vessel file so only the newest keep records are left, by rewriting the file without them
+/// Returns the number of records removed
+fn remove_oldest_records(file_path: &std::path::Path, keep: usize, settings: &Settings) -> Result<usize, Box<dyn std::error::Error>> {
+    // Read headers and records
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .flexible(true)
+        .from_reader(open_file_reader(file_path)?);
+    let headers = reader.headers()?.clone();
+    let records: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>()?;
+
+    // Nothing to do
+    if records.len() <= keep {
+        return Ok(0);
+    }
+    let removed = records.len() - keep;
+
+    // Replace the file with the newest records
+    let mut wtr = make_csv_writer_builder(settings)
+        .flexible(true)
+        .from_writer(Vec::new());
+    wtr.write_record(&headers)?;
+    for record in &records[removed..] {
+        wtr.write_record(record)?;
+    }
     wtr.flush()?;
+    replace_file_contents(file_path, wtr.get_ref())?;
 
-    // Return Ok
-    return Ok(());
+    // Return number of removed records
+    return Ok(removed);
 }
 
-/// Function that makes valid filenames for vessels.
-/// To make the filenames valid sometimes characters are replaced with an underscore
-fn make_filename(vessel_name: &str, suffix_number: u64) -> String {
-    // Init filename
-    let mut filename = format!("{}_{}.csv", vessel_name, suffix_number);
-    
-    // Replace all invalid characters with underscore
-    for invalid_char in INVALID_FILENAME_CHARACTERS.iter() {
-        filename = filename.replace(*invalid_char, "_");
+/// Starts a minimal HTTP server in a background thread serving /healthz on the given port
+/// /healthz returns 200 if the last successful collection is within the health_max_age window and 503 otherwise
+fn start_health_server(port: u16) {
+    let listener = match std::net::TcpListener::bind(("0.0.0.0", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Error starting health check server on port {}: {}\nContinuing without health check.", port, e);
+            return;
+        }
+    };
+    println!("Serving health check on http://0.0.0.0:{}/healthz", port);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => handle_health_request(s),
+                Err(e) => println!("Error accepting health check connection: {}", e),
+            }
+        }
+    });
+}
+
+/// Answers a single health check request
+fn handle_health_request(mut stream: std::net::TcpStream) {
+    // Read request line, only the path is needed
+    let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+    let mut buffer = [0u8; 1024];
+    let n = stream.read(&mut buffer).unwrap_or(0);
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    // Make response
+    let (status, body) = if path == "/healthz" {
+        get_health_status(time::UtcDateTime::now().unix_timestamp(), LAST_SUCCESS.load(Ordering::SeqCst), HEALTH_MAX_AGE_SECS.load(Ordering::SeqCst) as i64, SLEEPING_UNTIL.load(Ordering::SeqCst))
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+
+    // Send response, failing to answer is not critical
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Gets the status and body of the health check response from the time of the last successful collection, 0 if there has been none,
+/// and the time of the next cycle while sleeping outside active_hours, 0 if it has not slept outside them.
+/// Sleeping outside active_hours is healthy, and after waking up the first cycles get max_age seconds to succeed
+fn get_health_status(now: i64, last_success: i64, max_age: i64, sleeping_until: i64) -> (&'static str, String) {
+    let age = now - last_success;
+    if now < sleeping_until {
+        return ("200 OK", format!("OK, outside active_hours until {} UTC\n", format_datetime(time::UtcDateTime::from_unix_timestamp(sleeping_until).unwrap_or(time::UtcDateTime::UNIX_EPOCH))));
+    } else if sleeping_until > last_success && now - sleeping_until <= max_age {
+        return ("200 OK", format!("OK, active_hours started {} seconds ago\n", now - sleeping_until));
+    } else if last_success == 0 {
+        return ("503 Service Unavailable", "No successful collection yet\n".to_string());
+    } else if age > max_age {
+        return ("503 Service Unavailable", format!("Last successful collection was {} seconds ago, more than {} seconds\n", age, max_age));
     }
+    return ("200 OK", format!("OK, last successful collection was {} seconds ago\n", age));
+}
 
-    // Return filename
-    return filename;
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lock for tests that change the working directory, since the quarantine file and data folders are relative to it
+    static CURRENT_DIR_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Runs a test in a new empty temporary working directory, which is removed afterwards
+    fn in_temp_dir<F: FnOnce()>(name: &str, f: F) {
+        let _guard = CURRENT_DIR_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let previous_dir = std::env::current_dir().unwrap();
+        let dir = std::env::temp_dir().join(format!("aishub_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        std::env::set_current_dir(previous_dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        if let Err(e) = result {
+            std::panic::resume_unwind(e);
+        }
+    }
+
+    /// CSV response with the columns in the order AISHub sends them
+    const CSV_RESPONSE: &str = "MMSI,TSTAMP,LATITUDE,LONGITUDE,COG,SOG,HEADING,ROT,NAVSTAT,IMO,NAME,CALLSIGN,TYPE,DEVICE,A,B,C,D,DRAUGHT,DEST,ETA,PAC\n\
+        235000001,1760000200,60.1,5.2,123.4,10.5,120,0,0,9000001,TEST SHIP,ABC,70,1,10,20,5,5,65,OSLO,1234,1\n\
+        257000002,1760000300,59.9,10.7,45,3.2,44,-2,5,0,\"NORDIC, LADY\",LAXY,30,1,12,8,3,3,30,BERGEN,5678,0\n";
+
+    fn headers(names: &[&str]) -> csv::StringRecord {
+        return csv::StringRecord::from(names.to_vec());
+    }
+
+    #[test]
+    fn header_order_standard_columns() {
+        let order = get_header_order(&headers(&STANDARD_COLUMNS));
+        assert_eq!(order, (0..STANDARD_COLUMNS.len()).map(Some).collect::<Vec<Option<usize>>>());
+    }
+
+    #[test]
+    fn header_order_reordered_columns() {
+        let order = get_header_order(&headers(&["MMSI", "TSTAMP", "LATITUDE", "LONGITUDE", "COG", "SOG", "HEADING", "ROT", "NAVSTAT", "IMO", "NAME", "CALLSIGN", "TYPE", "DEVICE", "A", "B", "C", "D", "DRAUGHT", "DEST", "ETA", "PAC"]));
+        assert_eq!(order, vec![
+            Some(14), Some(15), Some(16), Some(11), Some(4), Some(17), Some(19), Some(18), Some(13), Some(20), Some(6),
+            Some(9), Some(2), Some(3), Some(0), Some(10), Some(8), Some(21), Some(7), Some(5), Some(1), Some(12),
+        ]);
+    }
+
+    #[test]
+    fn header_order_missing_and_unknown_columns() {
+        let order = get_header_order(&headers(&["TSTAMP", "EXTRA", "MMSI", "NAME"]));
+        let mut expected: Vec<Option<usize>> = vec![None; STANDARD_COLUMNS.len()];
+        expected[STANDARD_COLUMNS.iter().position(|c| *c == "MMSI").unwrap()] = Some(2);
+        expected[STANDARD_COLUMNS.iter().position(|c| *c == "NAME").unwrap()] = Some(3);
+        expected[STANDARD_COLUMNS.iter().position(|c| *c == "TSTAMP").unwrap()] = Some(0);
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn header_order_empty_headers() {
+        assert_eq!(get_header_order(&headers(&[])), vec![None; STANDARD_COLUMNS.len()]);
+    }
+
+    #[test]
+    fn record_to_vessel_info_reads_numbers_and_keeps_defaults() {
+        let h = headers(&["MMSI", "TSTAMP", "DRAUGHT", "NAME"]);
+        let vessel = record_to_vessel_info(&headers(&["235000001", "1760000200", "", "TEST SHIP"]), &get_header_order(&h)).unwrap();
+        assert_eq!(vessel.mmsi, 235000001);
+        assert_eq!(vessel.timestamp, 1760000200);
+        assert_eq!(vessel.draught, 0);
+        assert_eq!(vessel.imo, 0);
+    }
+
+    #[test]
+    fn record_to_vessel_info_rejects_invalid_numbers() {
+        let h = headers(&["MMSI", "TSTAMP", "DRAUGHT"]);
+        let e = record_to_vessel_info(&headers(&["235000001", "1760000200", "5.2"]), &get_header_order(&h)).unwrap_err();
+        assert_eq!(e, "Invalid DRAUGHT value: 5.2");
+    }
+
+    #[test]
+    fn parse_csv_response_quarantines_invalid_record() {
+        in_temp_dir("quarantine_invalid", || {
+            let body = "MMSI,TSTAMP,DRAUGHT\n235000001,1760000200,5.2\n257000002,1760000300,30\n";
+            let data = parse_csv_response(body, 1).unwrap();
+            assert_eq!(data.len(), 1);
+            assert_eq!(data[0].mmsi, 257000002);
+            let quarantine = fs::read_to_string(QUARANTINE_FILE).unwrap();
+            assert!(quarantine.contains("Invalid DRAUGHT value: 5.2"));
+            assert!(quarantine.contains("235000001,1760000200,5.2"));
+        });
+    }
+
+    #[test]
+    fn parse_xml_response_heterogeneous_attributes() {
+        let body = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <VESSELS>\n\
+            <status ERROR=\"false\" USERNAME=\"AH_TEST\" FORMAT=\"HUMAN\" RECORDS=\"3\"/>\n\
+            <vessel MMSI=\"235000001\" TSTAMP=\"1760000200\" LATITUDE=\"60.1\" LONGITUDE=\"5.2\" NAME=\"TEST SHIP\"/>\n\
+            <vessel MMSI=\"257000002\" TSTAMP=\"1760000300\" IMO=\"9000002\" DRAUGHT=\"30\" EXTRA=\"1\" NAME=\"NORDIC &amp; LADY\"/>\n\
+            <vessel TSTAMP=\"1760000400\" MMSI=\"258000003\"/>\n\
+            </VESSELS>\n";
+        let data = parse_xml_response(body).unwrap();
+        assert_eq!(data.len(), 3);
+        assert_eq!(data[0].mmsi, 235000001);
+        assert_eq!(data[0].latitude, "60.1");
+        assert_eq!(data[0].imo, 0);
+        assert_eq!(data[1].imo, 9000002);
+        assert_eq!(data[1].draught, 30);
+        assert_eq!(data[1].name, "NORDIC & LADY");
+        assert_eq!(data[1].latitude, "");
+        assert_eq!(data[2].mmsi, 258000003);
+        assert_eq!(data[2].timestamp, 1760000400);
+    }
+
+    #[test]
+    fn parse_xml_response_record_count_mismatch() {
+        let body = "<VESSELS><status ERROR=\"false\" RECORDS=\"2\"/><vessel MMSI=\"235000001\" TSTAMP=\"1760000200\"/></VESSELS>";
+        assert_eq!(parse_xml_response(body).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_csv_response_fixture() {
+        let data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].mmsi, 235000001);
+        assert_eq!(data[0].imo, 9000001);
+        assert_eq!(data[0].timestamp, 1760000200);
+        assert_eq!(data[0].latitude, "60.1");
+        assert_eq!(data[0].cog, Some(123.4));
+        assert_eq!(data[0].a, 10);
+        assert_eq!(data[0].eta, 1234);
+        assert_eq!(data[0].dest, "OSLO");
+        assert_eq!(data[1].name, "NORDIC, LADY");
+        assert_eq!(data[1].rot, "-2");
+        assert_eq!(data[1].pac, 0);
+    }
+
+    #[test]
+    fn parse_csv_response_ais_format() {
+        let body = "A,B,C,CALLSIGN,COG,D,DEST,DRAUGHT,DEVICE,ETA,HEADING,IMO,LATITUDE,LONGITUDE,MMSI,NAME,NAVSTAT,PAC,ROT,SOG,TSTAMP,TYPE\n\
+            10,20,5,ABC,1234,5,OSLO,65,1,1234,120,9000001,36060000,3120000,235000001,TEST SHIP,0,1,0,105,1760000200,70\n\
+            0,0,0,,3600,0,,0,1,0,511,0,54600000,108600000,257000002,,15,0,-128,1023,1760000300,0\n";
+        let data = parse_csv_response(body, 0).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].cog, Some(1234.0));
+        assert_eq!(data[0].sog, Some(105.0));
+        assert_eq!(data[0].latitude, "36060000");
+        assert_eq!(data[1].cog, Some(3600.0));
+        assert_eq!(data[1].heading, 511);
+    }
+
+    #[test]
+    fn parse_csv_response_headers_only() {
+        assert_eq!(parse_csv_response("MMSI,TSTAMP,LATITUDE,LONGITUDE\n", 1).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn parse_csv_response_truncated() {
+        // Cut in the middle of a line, the last record is short
+        let cut = &CSV_RESPONSE[..CSV_RESPONSE.len() - 20];
+        assert_eq!(parse_csv_response(cut, 1).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // Cut inside a quoted vessel name
+        let cut = &CSV_RESPONSE[..CSV_RESPONSE.find("NORDIC,").unwrap() + 7];
+        assert_eq!(parse_csv_response(cut, 1).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_csv_response_without_trailing_newline() {
+        let data = parse_csv_response(CSV_RESPONSE.trim_end(), 1).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1].pac, 0);
+    }
+
+    #[test]
+    fn get_data_in_batches_retries_truncated_response() {
+        let settings = Settings {
+            base_url: Some(serve_responses(vec![&CSV_RESPONSE[..CSV_RESPONSE.len() - 20], CSV_RESPONSE])),
+            retries_per_cycle: Some(1),
+            ..Settings::default()
+        };
+        let client = make_http_client(&settings).unwrap();
+        let batches = vec![(None, Some("235000001,257000002".to_string()))];
+        let (data, error) = get_data_in_batches(&batches, "AH_TEST", &client, &settings, &mut CycleTimings::default()).unwrap();
+        assert_eq!(data.len(), 2);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn convert_legacy_delimiter_mmsi_file() {
+        in_temp_dir("legacy_delimiter", || {
+            let settings = Settings::default();
+            let file_path = std::path::Path::new("TEST_SHIP_235000001.csv");
+            let legacy = format!("{}\n10,20,5,ABC,123.4,5,OSLO,65,1,1234,120,0,60.1,5.2,235000001,\"TEST, SHIP\",0,1,0,10.5,1760000200,70\n", STANDARD_COLUMNS.join(";"));
+            fs::write(file_path, legacy).unwrap();
+            assert!(convert_legacy_delimiter(file_path, &settings).unwrap());
+            let last_record = get_last_record(file_path, &settings).unwrap();
+            assert_eq!(last_record.timestamp().unwrap(), 1760000200);
+            assert_eq!(last_record.get("NAME"), Some("TEST, SHIP"));
+
+            // Converted files are left alone
+            assert!(!convert_legacy_delimiter(file_path, &settings).unwrap());
+        });
+    }
+
+    #[test]
+    fn convert_legacy_delimiter_rolls_over_unreadable_file() {
+        in_temp_dir("legacy_delimiter_unreadable", || {
+            let settings = Settings::default();
+            let file_path = std::path::Path::new("TEST_SHIP_235000001.csv");
+            fs::write(file_path, format!("{}\n10,20,5\n", STANDARD_COLUMNS.join(";"))).unwrap();
+            assert!(convert_legacy_delimiter(file_path, &settings).unwrap());
+            assert!(std::path::Path::new("TEST_SHIP_235000001.001.csv").exists());
+            assert_eq!(get_last_record(file_path, &settings).unwrap().count, 0);
+        });
+    }
+
+    #[test]
+    fn compact_data_keeps_records_without_timestamp() {
+        in_temp_dir("compact_data", || {
+            let settings = Settings::default();
+            fs::create_dir_all("data/mmsi").unwrap();
+            let header = STANDARD_COLUMNS.join(";");
+            let row = |name: &str, timestamp: &str| {
+                return STANDARD_COLUMNS.iter().map(|c| match *c {
+                    "MMSI" => "235000001",
+                    "NAME" => name,
+                    "TSTAMP" => timestamp,
+                    _ => "",
+                }).collect::<Vec<&str>>().join(";");
+            };
+            fs::write("data/mmsi/OLD_235000001.csv", format!("{}\n{}\n{}\n", header, row("OLD", ""), row("OLD", "1760000100"))).unwrap();
+            fs::write("data/mmsi/NEW_235000001.csv", format!("{}\n{}\n{}\n{}\n", header, row("NEW", ""), row("NEW", "1760000100"), row("NEW", "1760000200"))).unwrap();
+            compact_data(&settings).unwrap();
+
+            let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_path("data/mmsi/NEW_235000001.csv").unwrap();
+            let timestamp_index = STANDARD_COLUMNS.iter().position(|c| *c == "TSTAMP").unwrap();
+            let timestamps: Vec<String> = reader.records().map(|r| r.unwrap().get(timestamp_index).unwrap().to_string()).collect();
+            assert_eq!(timestamps, vec!["", "", "1760000100", "1760000200"]);
+        });
+    }
+
+    /// Serves the given response bodies in order, one per connection, on a local port and returns the base URL
+    fn serve_responses(bodies: Vec<&'static str>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ws.php", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0u8; 4096];
+                let _ = stream.read(&mut buffer);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        return url;
+    }
+
+    #[test]
+    fn get_data_in_batches_keeps_data_before_error() {
+        let mut settings = Settings::default();
+        settings.base_url = Some(serve_responses(vec![CSV_RESPONSE, "Too frequent requests!"]));
+        settings.batch_delay = Some(0);
+        // Do not change the settings file when the too frequent requests response is read
+        settings.max_update_interval = Some(settings.update_interval);
+        let client = make_http_client(&settings).unwrap();
+        let batches = vec![(None, Some("235000001".to_string())), (None, Some("257000002".to_string())), (None, Some("258000003".to_string()))];
+        let (data, error) = get_data_in_batches(&batches, "AH_TEST", &client, &settings, &mut CycleTimings::default()).unwrap();
+        assert_eq!(data.len(), 2);
+        assert!(matches!(error, Some(CollectError::RateLimited(_))));
+    }
+
+    #[test]
+    fn get_data_in_batches_error_without_data() {
+        let mut settings = Settings::default();
+        settings.base_url = Some(serve_responses(vec!["Too frequent requests!"]));
+        settings.max_update_interval = Some(settings.update_interval);
+        let client = make_http_client(&settings).unwrap();
+        let batches = vec![(None, Some("235000001".to_string()))];
+        let result = get_data_in_batches(&batches, "AH_TEST", &client, &settings, &mut CycleTimings::default());
+        assert!(matches!(result, Err(CollectError::RateLimited(_))));
+    }
+
+    #[test]
+    fn redact_api_key_in_middle_of_query() {
+        assert_eq!(redact_api_key("https://data.aishub.net/ws.php?format=1&username=AH_SECRET&output=csv"), "https://data.aishub.net/ws.php?format=1&username=***&output=csv");
+    }
+
+    #[test]
+    fn redact_api_key_at_end_of_query() {
+        assert_eq!(redact_api_key("error sending request for url (https://data.aishub.net/ws.php?format=1&username=AH_SECRET)"), "error sending request for url (https://data.aishub.net/ws.php?format=1&username=***)");
+        assert_eq!(redact_api_key("https://data.aishub.net/ws.php?username=AH_SECRET"), "https://data.aishub.net/ws.php?username=***");
+    }
+
+    #[test]
+    fn redact_api_key_url_encoded() {
+        let url = make_aishub_url(AISHUB_BASE_URL_DEFAULT, "AH key&=+", 1, "csv", 0, None, None, None, None, None, None, None).unwrap();
+        let redacted = redact_api_key(url.as_str());
+        assert!(!redacted.contains("AH"));
+        assert!(!redacted.contains("key"));
+        assert!(redacted.contains("username=***&format=1"));
+    }
+
+    #[test]
+    fn redact_api_key_without_key() {
+        assert_eq!(redact_api_key("https://data.aishub.net/ws.php?format=1&output=csv"), "https://data.aishub.net/ws.php?format=1&output=csv");
+        assert_eq!(redact_api_key(""), "");
+    }
+
+    #[test]
+    fn make_aishub_url_encodes_parameters() {
+        let url = make_aishub_url("https://data.aishub.net/ws.php", "AH_a&b=c+d", 1, "csv", 0, Some(-10.5), Some(10.0), None, None, Some("235000001,257000002"), None, Some(60)).unwrap();
+        assert_eq!(url, "https://data.aishub.net/ws.php?username=AH_a%26b%3Dc%2Bd&format=1&output=csv&compress=0&latmin=-10.5&latmax=10&mmsi=235000001%2C257000002&interval=60");
+        let parsed = reqwest::Url::parse(url.as_str()).unwrap();
+        let names: Vec<String> = parsed.query_pairs().map(|(name, _)| name.to_string()).collect();
+        assert_eq!(names, vec!["username", "format", "output", "compress", "latmin", "latmax", "mmsi", "interval"]);
+        assert_eq!(parsed.query_pairs().next().unwrap().1, "AH_a&b=c+d");
+    }
+
+    #[test]
+    fn check_headers_compares_names() {
+        let mut settings = Settings { decode_eta: true, ..Settings::default() };
+        let file_headers: csv::StringRecord = make_headers(&settings).into_iter().collect();
+        let file_path = std::path::Path::new("TEST_SHIP_235000001.csv");
+        assert!(check_headers(&file_headers, file_path, &settings).is_ok());
+
+        // Same number of columns, but a different optional column
+        settings.decode_eta = false;
+        settings.decode_rot = true;
+        assert_eq!(file_headers.len(), make_headers(&settings).len());
+        assert!(check_headers(&file_headers, file_path, &settings).is_err());
+
+        // Fewer columns
+        settings.decode_rot = false;
+        settings.cumulative_distance = true;
+        settings.sequence_column = true;
+        assert!(check_headers(&file_headers, file_path, &settings).is_err());
+    }
+
+    #[test]
+    fn save_vessel_records_repairs_truncated_row() {
+        in_temp_dir("repair_truncated_row", || {
+            let settings = Settings::default();
+            let data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+            let file_path = std::path::Path::new("TEST_SHIP_235000001.csv");
+            let mut older = parse_csv_response(CSV_RESPONSE, 1).unwrap().remove(0);
+            older.timestamp -= 100;
+
+            // Write a complete record followed by a truncated one, like after the program was killed while writing
+            make_empty_csv_file(file_path, &settings).unwrap();
+            let mut wtr = make_csv_writer_builder(&settings).from_writer(Vec::new());
+            write_records_to_file(&mut wtr, &[(&older, DerivedColumns::default())], &settings).unwrap();
+            let complete = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+            let mut contents = fs::read_to_string(file_path).unwrap();
+            contents.push_str(complete.as_str());
+            let complete_length = contents.len();
+            contents.push_str("10;20;5;ABC;12");
+            fs::write(file_path, contents).unwrap();
+
+            // Save a new record
+            let mut stats = SaveStats::default();
+            let rows = save_vessel_records(file_path, &[&data[0]], &std::collections::HashMap::new(), &mut stats, &settings).unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(stats.deduped, 0);
+
+            // The file is cut after the last complete record and the new record is appended after it
+            let contents = fs::read_to_string(file_path).unwrap();
+            assert!(contents.ends_with('\n'));
+            assert!(!contents.contains("10;20;5;ABC;12\n"));
+            let last_record = get_last_record(file_path, &settings).unwrap();
+            assert_eq!(last_record.count, 2);
+            assert_eq!(last_record.timestamp().unwrap(), data[0].timestamp);
+            assert!(contents.len() > complete_length);
+
+            // The truncated row is quarantined
+            let quarantine = fs::read_to_string(QUARANTINE_FILE).unwrap();
+            assert!(quarantine.contains("Incomplete last line"));
+            assert!(quarantine.contains("10;20;5;ABC;12"));
+        });
+    }
+
+    #[test]
+    fn save_vessel_records_returns_only_rows_under_max_records() {
+        in_temp_dir("max_records_rows", || {
+            let settings = Settings { max_records_per_ship: Some(2), ..Settings::default() };
+            let file_path = std::path::Path::new("TEST_SHIP_235000001.csv");
+            let mut records = Vec::new();
+            for offset in 0..3 {
+                let mut vessel = parse_csv_response(CSV_RESPONSE, 1).unwrap().remove(0);
+                vessel.timestamp += offset;
+                records.push(vessel);
+            }
+
+            // Only the two newest records are written and returned
+            let mut stats = SaveStats::default();
+            let rows = save_vessel_records(file_path, &records.iter().collect::<Vec<&VesselInfo>>(), &std::collections::HashMap::new(), &mut stats, &settings).unwrap();
+            assert_eq!(rows.iter().map(|(vessel, _)| vessel.timestamp).collect::<Vec<u64>>(), vec![records[1].timestamp, records[2].timestamp]);
+            assert_eq!(stats.skipped, 1);
+            assert_eq!(get_last_record(file_path, &settings).unwrap().count, 2);
+        });
+    }
+
+    #[test]
+    fn save_data_to_parquet_writes_part_files() {
+        in_temp_dir("parquet_part_files", || {
+            let settings = Settings::default();
+            fs::create_dir_all("data").unwrap();
+            let data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+            let mut stats = SaveStats::default();
+            assert_eq!(save_data_to_parquet(&data, &mut stats, &settings).unwrap().len(), 2);
+
+            // The same records again are duplicates and no part file is written
+            assert_eq!(save_data_to_parquet(&data, &mut stats, &settings).unwrap().len(), 0);
+            assert_eq!(stats.deduped, 2);
+
+            // A newer record goes to a new part file, the first one is left as it is
+            let mut newer = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+            newer.truncate(1);
+            newer[0].timestamp += 60;
+            assert_eq!(save_data_to_parquet(&newer, &mut stats, &settings).unwrap().len(), 1);
+            let day_folder = std::path::Path::new(PARQUET_FOLDER).join(format_date(time::UtcDateTime::from_unix_timestamp(data[0].timestamp as i64).unwrap()));
+            let mut part_files: Vec<std::path::PathBuf> = fs::read_dir(&day_folder).unwrap().map(|e| e.unwrap().path()).collect();
+            part_files.sort();
+            assert_eq!(part_files.len(), 2);
+            let mut rows = 0;
+            for part_file in &part_files {
+                let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(fs::File::open(part_file).unwrap()).unwrap().build().unwrap();
+                rows += reader.map(|b| b.unwrap().num_rows()).sum::<usize>();
+            }
+            assert_eq!(rows, 3);
+        });
+    }
+
+    #[test]
+    fn health_status_outside_active_hours() {
+        let now: i64 = 1760000000;
+        // Started outside active_hours, nothing collected yet
+        assert_eq!(get_health_status(now, 0, 900, now + 3600).0, "200 OK");
+        // Sleeping outside active_hours long after the last collection
+        assert_eq!(get_health_status(now, now - 36000, 900, now + 3600).0, "200 OK");
+        // Just woken up, the first cycle has max_age to succeed
+        assert_eq!(get_health_status(now, now - 36000, 900, now - 60).0, "200 OK");
+        assert_eq!(get_health_status(now, 0, 900, now - 60).0, "200 OK");
+        // Woken up but nothing collected since
+        assert_eq!(get_health_status(now, now - 36000, 900, now - 1000).0, "503 Service Unavailable");
+        assert_eq!(get_health_status(now, 0, 900, now - 1000).0, "503 Service Unavailable");
+    }
+
+    #[test]
+    fn health_status_without_active_hours() {
+        let now: i64 = 1760000000;
+        assert_eq!(get_health_status(now, 0, 900, 0).0, "503 Service Unavailable");
+        assert_eq!(get_health_status(now, now - 60, 900, 0).0, "200 OK");
+        assert_eq!(get_health_status(now, now - 1000, 900, 0).0, "503 Service Unavailable");
+    }
+
+    #[test]
+    fn fallback_too_frequent_requests_has_no_side_effects() {
+        in_temp_dir("fallback_rate_limited", || {
+            let settings = Settings {
+                save_raw_responses: Some("raw".to_string()),
+                ..Settings::default()
+            };
+            let template = format!("{}?mmsi={{mmsi}}", serve_responses(vec!["Too frequent requests!", CSV_RESPONSE]));
+            let client = make_http_client(&settings).unwrap();
+            let batches = vec![(None, Some("235000001".to_string()))];
+            let result = get_data_from_fallback(template.as_str(), &batches, &client, &settings, &mut CycleTimings::default());
+            assert!(matches!(result, Err(CollectError::RateLimited(_))));
+            assert!(!std::path::Path::new("settings.json").exists());
+            assert!(!std::path::Path::new("raw").exists());
+
+            // A normal response is parsed but not saved as an AISHub response either
+            let data = get_data_from_fallback(template.as_str(), &batches, &client, &settings, &mut CycleTimings::default()).unwrap();
+            assert_eq!(data.len(), 2);
+            assert!(!std::path::Path::new("raw").exists());
+        });
+    }
+
+    #[test]
+    fn make_fallback_url_encodes_values() {
+        let settings = Settings::default();
+        let url = make_fallback_url("https://example.com/ais?imo={imo}&mmsi={mmsi}", &settings, Some("9000001"), Some("235000001,257000002&x=1"));
+        assert_eq!(url, "https://example.com/ais?imo=9000001&mmsi=235000001%2C257000002%26x%3D1");
+    }
+
+    #[test]
+    fn retryable_write_errors() {
+        let storage_full: Box<dyn std::error::Error> = Box::new(io::Error::new(io::ErrorKind::StorageFull, "No space left on device"));
+        assert!(is_retryable_write_error(add_error_context("Error writing data to CSV file", storage_full).as_ref()));
+        let permission_denied = csv::Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(is_retryable_write_error(&permission_denied));
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        assert!(!is_retryable_write_error(&not_found));
+        let locked = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None);
+        assert!(is_retryable_write_error(&locked));
+    }
+
+    #[test]
+    fn save_data_with_policy_does_not_pause_on_column_mismatch() {
+        in_temp_dir("pause_column_mismatch", || {
+            let settings = Settings { on_write_error: "pause".to_string(), ..Settings::default() };
+            fs::create_dir_all("data/mmsi").unwrap();
+            fs::create_dir_all("data/imo").unwrap();
+            let data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+            let file_path = make_vessel_file_path(&data[0], "data/imo", data[0].imo, &settings).unwrap();
+            fs::write(&file_path, "A;B;C\n").unwrap();
+
+            // Returns the error right away instead of trying again until the file is fixed
+            let start = std::time::Instant::now();
+            assert!(save_data_with_policy(&data, &std::collections::HashMap::new(), &settings).is_err());
+            assert!(start.elapsed() < std::time::Duration::from_secs(WRITE_RETRY_DELAY));
+        });
+    }
+
+    #[test]
+    fn unidentified_records_saved_once() {
+        in_temp_dir("unidentified_once", || {
+            let settings = Settings { keep_unidentified: true, ..Settings::default() };
+            let mut data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+            data[0].imo = 0;
+            data[0].mmsi = 0;
+            let data = vec![data.remove(0)];
+
+            // Saved the first cycle, skipped as a duplicate when AISHub sends it again
+            let (_, stats) = save_data(&data, &std::collections::HashMap::new(), &settings).unwrap();
+            assert_eq!((stats.unidentified, stats.deduped), (1, 0));
+            let (_, stats) = save_data(&data, &std::collections::HashMap::new(), &settings).unwrap();
+            assert_eq!((stats.unidentified, stats.deduped), (0, 1));
+            assert_eq!(fs::read_to_string(UNIDENTIFIED_FILE).unwrap().lines().count(), 2);
+        });
+    }
+
+    #[test]
+    fn simulate_wait_for_tiny_speed() {
+        assert_eq!(get_simulate_wait(60, 2.0), Ok(std::time::Duration::from_secs(30)));
+        assert!(get_simulate_wait(60, 1e-300).is_err());
+        assert_eq!(get_simulate_wait(0, 1e-300), Ok(std::time::Duration::ZERO));
+    }
+
+    #[test]
+    fn reconcile_fills_in_imo_seen_earlier() {
+        let settings = Settings::default();
+        let mut mmsi_to_imo = std::collections::HashMap::new();
+        let mut data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+        reconcile_imo_numbers(&mut data, &mut mmsi_to_imo, &settings);
+        assert_eq!(data[1].imo, 0);
+
+        // A later record of the first ship without an IMO number gets the one seen earlier
+        let mut later = vec![parse_csv_response(CSV_RESPONSE, 1).unwrap().remove(0)];
+        later[0].imo = 0;
+        reconcile_imo_numbers(&mut later, &mut mmsi_to_imo, &settings);
+        assert_eq!(later[0].imo, 9000001);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let settings = Settings::default();
+        let data = parse_csv_response(CSV_RESPONSE, 1).unwrap();
+        assert_eq!(data.len(), 2);
+
+        // Write the records like a vessel file
+        let mut wtr = make_csv_writer_builder(&settings).from_writer(Vec::new());
+        wtr.write_record(make_headers(&settings)).unwrap();
+        let rows: Vec<(&VesselInfo, DerivedColumns)> = data.iter().map(|vessel| (vessel, DerivedColumns::default())).collect();
+        write_records_to_file(&mut wtr, &rows, &settings).unwrap();
+        let written = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+
+        // Read them back and compare the values that would be written
+        let mut rdr = csv::ReaderBuilder::new().delimiter(b';').from_reader(written.as_bytes());
+        let header_order = get_header_order(&rdr.headers().unwrap().clone());
+        assert!(header_order.iter().all(|i| i.is_some()));
+        let reparsed: Vec<VesselInfo> = rdr.records().map(|r| record_to_vessel_info(&r.unwrap(), &header_order).unwrap()).collect();
+        assert_eq!(reparsed.len(), data.len());
+        for (original, read) in data.iter().zip(reparsed.iter()) {
+            assert_eq!(make_record_values(original, &DerivedColumns::default(), &settings).unwrap(), make_record_values(read, &DerivedColumns::default(), &settings).unwrap());
+        }
+        assert_eq!(reparsed[1].name, "NORDIC, LADY");
+    }
+}